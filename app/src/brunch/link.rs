@@ -0,0 +1,183 @@
+// This module is responsible for the links to neighbouring peers which all tunnels through them share
+use bytes::Bytes;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use errors::*;
+
+// How many recently delivered sequence numbers are remembered per link to suppress duplicates
+const DUPLICATE_WINDOW: usize = 256;
+
+/**
+    Everything we share with one neighbour - the tunnels running over the link and its reliability state
+    The hostkey is the one the RPS named when we connected, neighbours which knocked on us stay anonymous.
+    Nothing checks that the neighbour actually holds it - it only keeps tunnels meant for different peers apart.
+    A link is not an authenticated connection: it is keyed by the neighbour's address, which anyone can claim.
+    Tunnels authenticate their hops themselves through the Auth handshake
+**/
+struct Link {
+    hostkey: Option<Bytes>,
+    // One per connection running over the link - a tunnel relayed to the peer it came from has two
+    attachments: HashSet<u64>,
+    // The peer's current epoch - see `Segment::Reliable`
+    epoch: Option<u32>,
+    delivered: VecDeque<u32>,
+    active: Instant
+}
+impl Link {
+    fn new(hostkey: Option<Bytes>) -> Link {
+        Link {
+            hostkey: hostkey,
+            attachments: HashSet::new(),
+            epoch: None,
+            delivered: VecDeque::with_capacity(DUPLICATE_WINDOW),
+            active: Instant::now()
+        }
+    }
+}
+
+/**
+    Links keyed by the neighbour's address - tunnels to the same neighbour are multiplexed over one
+    by the tunnel ids every datagram carries, instead of each looking like a connection of its own
+    Links are only created by attaching a tunnel - sources sending segments never get one by themselves,
+    so their duplicates are told apart in one window shared by all of them
+    Links without tunnels are kept around for a while in case another tunnel needs them
+**/
+pub struct Links {
+    links: HashMap<SocketAddr, Link>,
+    // Recently delivered (source, epoch, sequence) of sources without a link
+    strangers: VecDeque<(SocketAddr, u32, u32)>,
+    next_attachment: u64
+}
+impl Links {
+    pub fn new() -> Links {
+        Links {
+            links: HashMap::new(),
+            strangers: VecDeque::with_capacity(DUPLICATE_WINDOW),
+            next_attachment: 0
+        }
+    }
+
+    /** Marks the link to the peer as in use - peers without one don't get a link by it **/
+    pub fn touch(&mut self, peer: SocketAddr) {
+        if let Some(link) = self.links.get_mut(&peer) {
+            link.active = Instant::now();
+        }
+    }
+
+    /**
+        Adds a connection to the link to the peer - returns the attachment to detach it by
+        A peer known under another hostkey only gets a new link once none of its tunnels are left
+    **/
+    pub fn attach(&mut self, peer: SocketAddr, hostkey: Option<&Bytes>) -> Result<u64> {
+        let replaced = match (self.links.get(&peer), hostkey) {
+            (Some(link), Some(hostkey)) => match link.hostkey {
+                Some(ref known) if known != hostkey => {
+                    if !link.attachments.is_empty() {
+                        bail!("{} is linked under another hostkey by {} tunnel(s)", peer, link.attachments.len());
+                    }
+                    true
+                },
+                _ => false
+            },
+            _ => false
+        };
+        if replaced {
+            self.links.remove(&peer);
+        }
+
+        if !self.links.contains_key(&peer) {
+            let link = self.adopt(peer);
+            self.links.insert(peer, link);
+        }
+        let link = self.links.get_mut(&peer).expect("link was just created");
+        if let Some(hostkey) = hostkey {
+            link.hostkey = Some(hostkey.clone());
+        }
+        let attachment = self.next_attachment;
+        self.next_attachment += 1;
+        link.attachments.insert(attachment);
+        link.active = Instant::now();
+        Ok(attachment)
+    }
+
+    /** Creates the link to a former stranger - what it delivered before still counts as delivered **/
+    fn adopt(&mut self, peer: SocketAddr) -> Link {
+        let mut link = Link::new(None);
+        let strangers = mem::replace(&mut self.strangers, VecDeque::with_capacity(DUPLICATE_WINDOW));
+        for (source, epoch, sequence) in strangers {
+            if source != peer {
+                self.strangers.push_back((source, epoch, sequence));
+                continue;
+            }
+            if link.epoch != Some(epoch) {
+                link.epoch = Some(epoch);
+                link.delivered.clear();
+            }
+            link.delivered.push_back(sequence);
+        }
+        link
+    }
+
+    pub fn detach(&mut self, peer: SocketAddr, attachment: u64) {
+        if let Some(link) = self.links.get_mut(&peer) {
+            link.attachments.remove(&attachment);
+        }
+    }
+
+    /** Amount of connections running over the link to the peer - one per tunnel unless it is relayed back **/
+    pub fn tunnels(&self, peer: SocketAddr) -> usize {
+        self.links.get(&peer).map_or(0, |link| link.attachments.len())
+    }
+
+    /**
+        Returns whether the sequence number was delivered over the link before and remembers it otherwise
+        A new epoch means the peer restarted and counts from scratch, so everything it sent before is forgotten
+    **/
+    pub fn delivered(&mut self, peer: SocketAddr, epoch: u32, sequence: u32) -> bool {
+        let link = match self.links.get_mut(&peer) {
+            Some(link) => link,
+            None => {
+                if self.strangers.contains(&(peer, epoch, sequence)) {
+                    return true;
+                }
+                if self.strangers.len() == DUPLICATE_WINDOW {
+                    self.strangers.pop_front();
+                }
+                self.strangers.push_back((peer, epoch, sequence));
+                return false;
+            }
+        };
+        if link.epoch != Some(epoch) {
+            link.epoch = Some(epoch);
+            link.delivered.clear();
+        }
+        if link.delivered.contains(&sequence) {
+            return true;
+        }
+        if link.delivered.len() == DUPLICATE_WINDOW {
+            link.delivered.pop_front();
+        }
+        link.delivered.push_back(sequence);
+        false
+    }
+
+    /** Forgets links without tunnels which were idle for longer than the timeout - busy ones are kept **/
+    pub fn reap<F>(&mut self, idle: Duration, busy: F) -> Vec<SocketAddr> where F: Fn(SocketAddr) -> bool {
+        let now = Instant::now();
+        let reaped: Vec<SocketAddr> = self.links.iter()
+            .filter(|&(&peer, link)| {
+                link.attachments.is_empty() && now.duration_since(link.active) >= idle && !busy(peer)
+            })
+            .map(|(&peer, _)| peer)
+            .collect();
+
+        for peer in &reaped {
+            self.links.remove(peer);
+        }
+        reaped
+    }
+}
@@ -0,0 +1,307 @@
+use mio::tcp::TcpListener;
+use mio::net::UdpSocket;
+use stoppable_thread;
+use stoppable_thread::{StoppableHandle, SimpleAtomicBool};
+
+use std::net;
+use std::net::{SocketAddr};
+use std::collections::VecDeque;
+use std::sync::{mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::*;
+use messages::{WireFormat, decode_message, encode_message};
+use messages::p2p::{P2P, Datagram, Segment};
+use config;
+use core;
+use core::{StreamType, ListenerStatus, ClientStatus};
+
+mod link;
+mod reactor;
+mod transport;
+#[cfg(test)]
+mod tests;
+
+use self::reactor::{Reactor, Framing, Dispatch};
+pub use self::transport::Transport;
+
+// How long a listener thread waits for socket events before checking for replies & shutdown
+const POLL_INTERVAL: u64 = 100;
+
+/** Writes the message to the API client it is meant for - messages are never written to every client **/
+fn write_api_message(reactor: &mut Reactor, packed_message: StreamType) -> Result<()> {
+    let (client, message) = match packed_message {
+        StreamType::API(Some(client), message) => (client, message),
+        StreamType::API(None, message) => bail!("no API client is connected to receive {}", message.name()),
+        _ => bail!("only API messages are allowed here")
+    };
+    if !reactor.streams().contains(&client) {
+        bail!("API client {:?} is gone", client);
+    }
+
+    if reactor.send(client, &encode_message(message)?).is_err() {
+        reactor.close(client);
+    }
+    Ok(())
+}
+
+// Backoff between listener restarts, doubled on every consecutive failure
+const INITIAL_BACKOFF: u64 = 100;
+const MAX_BACKOFF: u64 = 10000;
+// A listener which ran at least this long is considered healthy again
+const HEALTHY_RUNTIME: u64 = 60000;
+
+/**
+    Keeps a listener running - failed listeners are restarted with backoff
+    Every failure is reported to the core, which gives up once the failure budget is used up
+**/
+fn supervise<F>(name: &'static str, failure_budget: u32, tx: mpsc::SyncSender<StreamType>, mut listener: F)
+    -> StoppableHandle<()>
+    where F: FnMut(&SimpleAtomicBool) -> Result<()> + Send + 'static
+{
+    stoppable_thread::spawn(move |should_die| {
+        let mut failures = 0;
+        let mut backoff = INITIAL_BACKOFF;
+
+        while !should_die.get() {
+            let started = Instant::now();
+
+            let error = match listener(should_die) {
+                Ok(()) => break,
+                Err(e) => e
+            };
+
+            if started.elapsed() > Duration::from_millis(HEALTHY_RUNTIME) {
+                failures = 0;
+                backoff = INITIAL_BACKOFF;
+            }
+            failures += 1;
+
+            if failures > failure_budget {
+                let _ = tx.send(StreamType::Listener(ListenerStatus::Abandoned(name, format!("{}", error))));
+                break;
+            }
+            if tx.send(StreamType::Listener(ListenerStatus::Failed(name, format!("{}", error)))).is_err() {
+                break;
+            }
+
+            let restart_at = Instant::now() + Duration::from_millis(backoff);
+            while !should_die.get() && Instant::now() < restart_at {
+                thread::sleep(Duration::from_millis(10));
+            }
+            backoff = ::std::cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    })
+}
+
+/** Clients coming and going are reported to the core along with their requests **/
+fn hand_api_dispatch_to_core(tx: &mpsc::SyncSender<StreamType>, dispatch: Dispatch) -> Result<()> {
+    let stream = match dispatch {
+        Dispatch::Accepted(client) => StreamType::Client(ClientStatus::Connected(client)),
+        Dispatch::Closed(client) => StreamType::Client(ClientStatus::Disconnected(client)),
+        Dispatch::Received(client, frame) => StreamType::API(Some(client), decode_message(frame)?),
+        Dispatch::Datagram(..) => bail!("API channel received a datagram")
+    };
+    tx.send(stream).chain_err(|| "sending stream to core channel failed")?;
+    Ok(())
+}
+
+/** Serves API clients - requests are handed to the core, replies are written back to the clients **/
+fn run_api_channel(socket: SocketAddr, tx: &mpsc::SyncSender<StreamType>, ry: &mpsc::Receiver<StreamType>,
+    should_die: &SimpleAtomicBool) -> Result<()> {
+
+    let mut reactor = Reactor::new()?;
+    reactor.listen(TcpListener::bind(&socket)
+        .chain_err(|| ErrorKind::Io("creating tcp listener".to_string()))?, Framing::LengthPrefixed)?;
+
+    note!(format!("listening for API connections at {}", socket));
+    let _ = tx.send(StreamType::Listener(ListenerStatus::Running("API")));
+
+    let result = || -> Result<()> {
+        while !should_die.get() {
+            for dispatch in reactor.turn(Duration::from_millis(POLL_INTERVAL))? {
+                trace_labeled_error!( "API message could not be handled", {
+                    hand_api_dispatch_to_core(tx, dispatch)?;
+                });
+            }
+
+            for packed_message in ry.try_iter() {
+                trace_labeled_error!( "API stream encountered a problem", {
+                    write_api_message(&mut reactor, packed_message)?;
+                });
+            }
+        };
+
+        // Replies queued up during shutdown still have to reach the API
+        for packed_message in ry.try_iter() {
+            trace_labeled_error!( "API stream encountered a problem while flushing", {
+                write_api_message(&mut reactor, packed_message)?;
+            });
+        }
+        Ok(())
+    }();
+
+    // Clients of a failed channel are gone along with it
+    for client in reactor.streams() {
+        let _ = tx.send(StreamType::Client(ClientStatus::Disconnected(client)));
+    }
+    result
+}
+
+fn create_api_channel(socket: SocketAddr, failure_budget: u32, tx: mpsc::SyncSender<StreamType>,
+    ry: mpsc::Receiver<StreamType>) -> StoppableHandle<()> {
+    let status_tx = tx.clone();
+    supervise("API", failure_budget, status_tx, move |should_die| {
+        run_api_channel(socket, &tx, &ry, should_die).chain_err(|| "failed to create API tcp channel")
+    })
+}
+
+/**
+    Serves other nodes over the node's udp socket - received datagrams are handed to the core
+    along with where they came from, unacknowledged control messages are retransmitted
+    and idle links reaped every turn
+**/
+fn run_p2p_listener(transport: &Transport, link_idle_timeout: Duration, channel_capacity: usize,
+    tx: &mpsc::SyncSender<StreamType>, should_die: &SimpleAtomicBool) -> Result<()> {
+
+    let mut reactor = Reactor::new()?;
+    reactor.bind(transport.socket().try_clone().and_then(UdpSocket::from_socket)
+        .chain_err(|| ErrorKind::Io("sharing udp socket".to_string()))?)?;
+    let _ = tx.send(StreamType::Listener(ListenerStatus::Running("P2P")));
+
+    let mut backlog = Backlog::new(channel_capacity);
+    while !should_die.get() {
+        backlog.flush(tx)?;
+        for dispatch in reactor.turn(Duration::from_millis(POLL_INTERVAL))? {
+            if let Dispatch::Datagram(_, source, frame) = dispatch {
+                trace_labeled_error!( "P2P message could not be handled", {
+                    if let Some(datagram) = transport.receive(source, Segment::decode(frame)?)? {
+                        backlog.hand_to_core(tx, source, datagram)?;
+                    }
+                });
+            }
+        }
+
+        transport.retransmit()?;
+        transport.reap(link_idle_timeout)?;
+    }
+
+    Ok(())
+}
+
+/**
+    Tunnel data is dropped while the core is busy - its senders have to cope with lost datagrams anyway
+    Control messages were acknowledged already, so they wait in a backlog of the core channel's size instead.
+    The listener never waits for the core - links keep being acknowledged and retransmitted meanwhile
+**/
+struct Backlog {
+    streams: VecDeque<StreamType>,
+    capacity: usize
+}
+impl Backlog {
+    fn new(capacity: usize) -> Backlog {
+        Backlog {
+            streams: VecDeque::new(),
+            capacity: capacity
+        }
+    }
+
+    fn hand_to_core(&mut self, tx: &mpsc::SyncSender<StreamType>, source: SocketAddr, datagram: Datagram)
+        -> Result<()> {
+        self.flush(tx)?;
+        let stream = StreamType::P2P(source, datagram);
+        // Streams waiting already go first
+        let stream = if self.streams.is_empty() {
+            match tx.try_send(stream) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::TrySendError::Full(stream)) => stream,
+                Err(mpsc::TrySendError::Disconnected(_)) => bail!("sending stream to core channel failed")
+            }
+        } else {
+            stream
+        };
+
+        let is_data = match stream {
+            StreamType::P2P(_, ref datagram) => datagram.message.message_type == P2P::Data,
+            _ => false
+        };
+        if is_data || self.streams.len() >= self.capacity {
+            note!(format!("core is congested - dropping {} from {}", stream.name(), source));
+        } else {
+            self.streams.push_back(stream);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, tx: &mpsc::SyncSender<StreamType>) -> Result<()> {
+        while let Some(stream) = self.streams.pop_front() {
+            match tx.try_send(stream) {
+                Ok(()) => (),
+                Err(mpsc::TrySendError::Full(stream)) => {
+                    self.streams.push_front(stream);
+                    break;
+                },
+                Err(mpsc::TrySendError::Disconnected(_)) => bail!("sending stream to core channel failed")
+            }
+        }
+        Ok(())
+    }
+}
+
+fn create_p2p_listener(transport: Transport, link_idle_timeout: Duration, channel_capacity: usize,
+    failure_budget: u32, tx: mpsc::SyncSender<StreamType>) -> StoppableHandle<()> {
+    let status_tx = tx.clone();
+    supervise("P2P", failure_budget, status_tx, move |should_die| {
+        run_p2p_listener(&transport, link_idle_timeout, channel_capacity, &tx, should_die)
+            .chain_err(|| "failed to create P2P listener")
+    })
+}
+
+/** The node's one udp socket - all P2P messages are sent from and received on it **/
+fn bind_udp_socket(socket: SocketAddr) -> Result<net::UdpSocket> {
+    Ok(net::UdpSocket::bind(&socket).chain_err(|| ErrorKind::Io(format!("binding udp socket at {}", socket)))?)
+}
+
+/**
+    Brunch: Because nothing beats breakfast & lunch like good ol' garlic bread
+    Connects tcp channels to the core module via the core channel
+    Signals and other controls reach the core through `tx` as well
+    The core channel is bounded - API clients are no longer read from while it is full. The channel back to
+    the API clients is not, as state machines must never wait for the API thread while it waits for the core
+**/
+pub fn start (conf: config::Config, tx: mpsc::SyncSender<StreamType>, rx: mpsc::Receiver<StreamType>)
+    -> Result<()> {
+    status!("Brunch is served!");
+
+    let (ty, ry) = mpsc::channel();
+    let transport = Transport::new(bind_udp_socket(conf.p2p_socket)?);
+
+    let api_thread_handle = {
+        let conf = conf.clone();
+        let tx = tx.clone();
+
+        create_api_channel(conf.api_socket, conf.listener_failure_budget, tx, ry)
+    };
+
+    let p2p_thread_handle = {
+        let conf = conf.clone();
+        let tx = tx.clone();
+
+        let transport = transport.clone();
+
+        create_p2p_listener(transport, conf.link_idle_timeout, conf.channel_capacity, conf.listener_failure_budget, tx)
+    };
+
+    let core_result = core::start(&rx, ty, transport, conf).chain_err(|| "core routine failed to shut down cleanly");
+
+    // The core has stopped accepting by now - the API thread flushes outstanding replies before it exits
+    if p2p_thread_handle.stop().join().is_err() {
+        status!("P2P listener did not exit cleanly", "warn");
+    }
+    if api_thread_handle.stop().join().is_err() {
+        status!("API channel did not exit cleanly", "warn");
+    }
+
+    core_result
+}
@@ -0,0 +1,290 @@
+// This module is responsible for multiplexing all sockets of a thread over a single mio Poll
+use mio::tcp::{TcpListener, TcpStream};
+use mio::net::UdpSocket;
+use mio::{Poll, PollOpt, Token, Events, Ready};
+use bytes::{Bytes, BytesMut};
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use errors::*;
+
+// Streams which don't frame their messages can't buffer more than a single message could be long
+const MAX_UNFRAMED_LENGTH: usize = 65535;
+// Length prefixed messages can't be shorter than their header
+const MIN_FRAME_LENGTH: usize = 4;
+const READ_CHUNK_LENGTH: usize = 4096;
+// Large enough for any UDP payload - longer datagrams can't exist
+const MAX_DATAGRAM_LENGTH: usize = 65535;
+
+/** How the bytes received on a stream are cut into messages **/
+#[derive(Clone, Copy, PartialEq)]
+pub enum Framing {
+    /** Every message starts with its own length as a two byte header (API messages) **/
+    LengthPrefixed,
+    /** The whole stream is a single message, complete once the other side closes it **/
+    UntilClosed
+}
+
+/** Outcome of a single turn of the reactor **/
+pub enum Dispatch {
+    Accepted(Token),
+    Received(Token, Bytes),
+    Datagram(Token, SocketAddr, Bytes),
+    Closed(Token)
+}
+
+struct Stream {
+    stream: TcpStream,
+    framing: Framing,
+    incomming: BytesMut,
+    outgoing: BytesMut
+}
+
+enum Source {
+    Listener(TcpListener, Framing),
+    Stream(Stream),
+    Socket(UdpSocket)
+}
+
+/** Owns a single Poll along with every listener and stream registered on it **/
+pub struct Reactor {
+    poll: Poll,
+    events: Events,
+    sources: HashMap<Token, Source>,
+    next_token: usize
+}
+impl Reactor {
+    pub fn new() -> Result<Reactor> {
+        Ok(Reactor {
+            poll: Poll::new().chain_err(|| ErrorKind::Io("creating poll".to_string()))?,
+            events: Events::with_capacity(1024),
+            sources: HashMap::new(),
+            next_token: 0
+        })
+    }
+
+    fn allocate_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    /** Accepts connections on the listener - accepted streams use the given framing **/
+    pub fn listen(&mut self, listener: TcpListener, framing: Framing) -> Result<Token> {
+        let token = self.allocate_token();
+        self.poll.register(&listener, token, Ready::readable(), PollOpt::edge())
+            .chain_err(|| ErrorKind::Io("registering listener on poll".to_string()))?;
+        self.sources.insert(token, Source::Listener(listener, framing));
+        Ok(token)
+    }
+
+    /** Receives datagrams on the bound socket - every datagram is dispatched on its own **/
+    pub fn bind(&mut self, socket: UdpSocket) -> Result<Token> {
+        let token = self.allocate_token();
+        self.poll.register(&socket, token, Ready::readable(), PollOpt::edge())
+            .chain_err(|| ErrorKind::Io("registering udp socket on poll".to_string()))?;
+        self.sources.insert(token, Source::Socket(socket));
+        Ok(token)
+    }
+
+    /** Adds an already connected stream, e.g. one to a peer **/
+    pub fn add_stream(&mut self, stream: TcpStream, framing: Framing) -> Result<Token> {
+        let token = self.allocate_token();
+        self.poll.register(&stream, token, Ready::readable() | Ready::writable(), PollOpt::edge())
+            .chain_err(|| ErrorKind::Io("registering stream on poll".to_string()))?;
+        self.sources.insert(token, Source::Stream(Stream {
+            stream: stream,
+            framing: framing,
+            incomming: BytesMut::with_capacity(READ_CHUNK_LENGTH),
+            outgoing: BytesMut::with_capacity(0)
+        }));
+        Ok(token)
+    }
+
+    /** Tokens of all open streams **/
+    pub fn streams(&self) -> Vec<Token> {
+        self.sources.iter()
+            .filter_map(|(token, source)| match *source {
+                Source::Stream(_) => Some(*token),
+                Source::Listener(..) | Source::Socket(_) => None
+            })
+            .collect()
+    }
+
+    /** Queues bytes for the stream - whatever the socket accepts right away is written immediately **/
+    pub fn send(&mut self, token: Token, bytes: &[u8]) -> Result<()> {
+        match self.sources.get_mut(&token) {
+            Some(&mut Source::Stream(ref mut stream)) => stream.outgoing.extend_from_slice(bytes),
+            _ => bail!(ErrorKind::Io(format!("writing to unknown stream {:?}", token)))
+        }
+        self.flush(token)
+    }
+
+    /** Deregisters the listener or stream and closes it **/
+    pub fn close(&mut self, token: Token) {
+        if let Some(source) = self.sources.remove(&token) {
+            // The socket is closed on drop either way
+            let _ = match source {
+                Source::Listener(ref listener, _) => self.poll.deregister(listener),
+                Source::Stream(ref stream) => self.poll.deregister(&stream.stream),
+                Source::Socket(ref socket) => self.poll.deregister(socket)
+            };
+        }
+    }
+
+    /** Waits up to `timeout` for readiness and dispatches accepts, reads and writes per token **/
+    pub fn turn(&mut self, timeout: Duration) -> Result<Vec<Dispatch>> {
+        self.poll.poll(&mut self.events, Some(timeout))
+            .chain_err(|| ErrorKind::Io("polling".to_string()))?;
+
+        let ready: Vec<(Token, Ready)> = self.events.iter()
+            .map(|event| (event.token(), event.readiness()))
+            .collect();
+
+        let mut dispatched = vec![];
+        for (token, readiness) in ready {
+            match self.sources.get(&token) {
+                Some(&Source::Listener(..)) => {
+                    self.accept(token, &mut dispatched)?;
+                    continue;
+                },
+                Some(&Source::Socket(ref socket)) => {
+                    receive_datagrams(token, socket, &mut dispatched)?;
+                    continue;
+                },
+                Some(&Source::Stream(_)) => (),
+                None => continue
+            };
+
+            if readiness.is_readable() {
+                self.read(token, &mut dispatched);
+            }
+            if readiness.is_writable() && self.sources.contains_key(&token) && self.flush(token).is_err() {
+                self.close(token);
+                dispatched.push(Dispatch::Closed(token));
+            }
+        }
+
+        Ok(dispatched)
+    }
+
+    fn accept(&mut self, token: Token, dispatched: &mut Vec<Dispatch>) -> Result<()> {
+        loop {
+            let accepted = match self.sources.get(&token) {
+                Some(&Source::Listener(ref listener, framing)) => match listener.accept() {
+                    Ok((stream, _)) => (stream, framing),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(e).chain_err(|| ErrorKind::Io("accepting connection".to_string()))
+                },
+                _ => return Ok(())
+            };
+
+            let (stream, framing) = accepted;
+            let stream_token = self.add_stream(stream, framing)?;
+            dispatched.push(Dispatch::Accepted(stream_token));
+        }
+    }
+
+    /** Reads everything available - streams which fail or end are closed after their last message **/
+    fn read(&mut self, token: Token, dispatched: &mut Vec<Dispatch>) {
+        let closed = match self.sources.get_mut(&token) {
+            Some(&mut Source::Stream(ref mut stream)) => {
+                let closed = stream.read_available();
+                for frame in stream.frames(closed) {
+                    dispatched.push(Dispatch::Received(token, frame));
+                }
+                closed || stream.is_malformed()
+            },
+            _ => return
+        };
+
+        if closed {
+            self.close(token);
+            dispatched.push(Dispatch::Closed(token));
+        }
+    }
+
+    fn flush(&mut self, token: Token) -> Result<()> {
+        match self.sources.get_mut(&token) {
+            Some(&mut Source::Stream(ref mut stream)) => stream.flush(),
+            _ => Ok(())
+        }
+    }
+}
+
+/** Receives until the socket would block - the readiness is edge triggered **/
+fn receive_datagrams(token: Token, socket: &UdpSocket, dispatched: &mut Vec<Dispatch>) -> Result<()> {
+    let mut buffer = [0; MAX_DATAGRAM_LENGTH];
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((length, source)) => dispatched.push(Dispatch::Datagram(token, source,
+                Bytes::from(&buffer[..length]))),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).chain_err(|| ErrorKind::Io("receiving datagram".to_string()))
+        }
+    }
+}
+
+impl Stream {
+    /** Returns whether the stream has ended **/
+    fn read_available(&mut self) -> bool {
+        let mut chunk = [0; READ_CHUNK_LENGTH];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return true,
+                Ok(length) => self.incomming.extend_from_slice(&chunk[..length]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return true
+            }
+        }
+    }
+
+    fn frames(&mut self, closed: bool) -> Vec<Bytes> {
+        let mut frames = vec![];
+        match self.framing {
+            Framing::LengthPrefixed => {
+                while self.incomming.len() >= 2 {
+                    let length = (self.incomming[0] as usize) << 8 | self.incomming[1] as usize;
+                    if length < MIN_FRAME_LENGTH || self.incomming.len() < length {
+                        break;
+                    }
+                    frames.push(self.incomming.split_to(length).freeze());
+                }
+            },
+            Framing::UntilClosed => {
+                if closed && !self.incomming.is_empty() {
+                    frames.push(self.incomming.take().freeze());
+                }
+            }
+        }
+        frames
+    }
+
+    /** Streams which can't be cut into messages anymore have to be dropped **/
+    fn is_malformed(&self) -> bool {
+        match self.framing {
+            Framing::LengthPrefixed => self.incomming.len() >= 2 &&
+                ((self.incomming[0] as usize) << 8 | self.incomming[1] as usize) < MIN_FRAME_LENGTH,
+            Framing::UntilClosed => self.incomming.len() > MAX_UNFRAMED_LENGTH
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => bail!(ErrorKind::Io("writing stream".to_string())),
+                Ok(length) => { self.outgoing.split_to(length); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).chain_err(|| ErrorKind::Io("writing stream".to_string()))
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+use bytes::{Bytes, BytesMut};
+use mio::Token;
+use mio::tcp::TcpListener;
+
+use std::io::{Read, Write};
+use std::net;
+use std::net::{SocketAddr, Shutdown, TcpStream};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use brunch::link::Links;
+use brunch::reactor::{Reactor, Framing, Dispatch};
+use brunch::transport::Transport;
+use brunch::{Backlog, write_api_message};
+use core::StreamType;
+use messages::{Message, WireFormat};
+use messages::p2p::{P2P, P2PMessage, Datagram, Segment};
+use messages::rps::{Rps, RpsQuery};
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn tunnels_to_the_same_peer_share_its_link() {
+    let mut links = Links::new();
+    let hostkey = Bytes::from_static(b"hostkey");
+
+    links.attach(peer(1), Some(&hostkey)).unwrap();
+    links.attach(peer(1), Some(&hostkey)).unwrap();
+    links.attach(peer(2), None).unwrap();
+    assert_eq!(links.tunnels(peer(1)), 2);
+
+    // Duplicates are told apart per link
+    assert!(!links.delivered(peer(1), 1, 7));
+    assert!(links.delivered(peer(1), 1, 7));
+    assert!(!links.delivered(peer(2), 1, 7));
+}
+
+#[test]
+fn strangers_only_get_a_link_once_a_tunnel_is_attached() {
+    let mut links = Links::new();
+    for port in 1..1000 {
+        links.touch(peer(port));
+        assert!(!links.delivered(peer(port), 1, 7));
+    }
+    assert!(links.reap(Duration::from_millis(0), |_| false).is_empty());
+
+    // Duplicates of a stranger are still caught, even once its knock got it a link
+    assert!(links.delivered(peer(999), 1, 7));
+    links.attach(peer(999), None).unwrap();
+    assert!(links.delivered(peer(999), 1, 7));
+    assert!(!links.delivered(peer(999), 1, 8));
+}
+
+#[test]
+fn connections_of_one_tunnel_to_the_same_peer_are_detached_separately() {
+    let mut links = Links::new();
+    // A relay whose previous and next hop are the same node
+    let previous = links.attach(peer(1), None).unwrap();
+    let next = links.attach(peer(1), None).unwrap();
+
+    links.detach(peer(1), previous);
+    assert_eq!(links.tunnels(peer(1)), 1);
+    links.detach(peer(1), next);
+    assert_eq!(links.tunnels(peer(1)), 0);
+}
+
+#[test]
+fn peers_only_change_their_hostkey_once_their_tunnels_are_gone() {
+    let mut links = Links::new();
+    let hostkey = Bytes::from_static(b"hostkey");
+    let other = Bytes::from_static(b"other");
+
+    let attachment = links.attach(peer(1), Some(&hostkey)).unwrap();
+    assert!(links.attach(peer(1), Some(&other)).is_err());
+
+    links.detach(peer(1), attachment);
+    links.attach(peer(1), Some(&other)).unwrap();
+    assert_eq!(links.tunnels(peer(1)), 1);
+}
+
+#[test]
+fn only_idle_links_without_tunnels_are_reaped() {
+    let mut links = Links::new();
+    links.attach(peer(1), None).unwrap();
+    let attachment = links.attach(peer(2), None).unwrap();
+    for port in 3..5 {
+        let attachment = links.attach(peer(port), None).unwrap();
+        links.detach(peer(port), attachment);
+    }
+    links.detach(peer(2), attachment);
+
+    let mut reaped = links.reap(Duration::from_millis(0), |peer| peer.port() == 4);
+    reaped.sort();
+    assert_eq!(reaped, vec![peer(2), peer(3)]);
+    assert!(links.reap(Duration::from_secs(60), |_| false).is_empty());
+}
+
+/** A reactor serving length prefixed streams and a client connected to it **/
+fn api_channel() -> (Reactor, TcpStream) {
+    let listener = net::TcpListener::bind(peer(0)).unwrap();
+    let address = listener.local_addr().unwrap();
+    let listener = TcpListener::from_listener(listener, &address).unwrap();
+
+    let mut reactor = Reactor::new().unwrap();
+    reactor.listen(listener, Framing::LengthPrefixed).unwrap();
+    (reactor, TcpStream::connect(address).unwrap())
+}
+
+/** Turns the reactor until it dispatched `until` or a second passed - returns everything it dispatched **/
+fn turn<F>(reactor: &mut Reactor, until: F) -> Vec<Dispatch> where F: Fn(&Dispatch) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut dispatched = vec![];
+    while Instant::now() < deadline && !dispatched.iter().any(&until) {
+        dispatched.extend(reactor.turn(Duration::from_millis(10)).unwrap());
+    }
+    dispatched
+}
+
+fn received(dispatched: &[Dispatch]) -> Vec<Bytes> {
+    dispatched.iter()
+        .filter_map(|dispatch| match *dispatch {
+            Dispatch::Received(_, ref frame) => Some(frame.clone()),
+            _ => None
+        })
+        .collect()
+}
+
+fn is_closed(dispatch: &Dispatch) -> bool {
+    match *dispatch {
+        Dispatch::Closed(_) => true,
+        _ => false
+    }
+}
+
+#[test]
+fn frames_split_across_reads_are_reassembled() {
+    let (mut reactor, mut client) = api_channel();
+
+    client.write_all(&[0x00, 0x06, 0x02]).unwrap();
+    assert!(received(&turn(&mut reactor, |_| false)).is_empty());
+
+    client.write_all(&[0x38, 0xaa, 0xbb, 0x00, 0x04, 0x02, 0x1c]).unwrap();
+    let frames = received(&turn(&mut reactor, |dispatch| match *dispatch {
+        Dispatch::Received(_, ref frame) => frame.len() == 4,
+        _ => false
+    }));
+    assert_eq!(frames, vec![
+        Bytes::from_static(&[0x00, 0x06, 0x02, 0x38, 0xaa, 0xbb]),
+        Bytes::from_static(&[0x00, 0x04, 0x02, 0x1c])
+    ]);
+}
+
+#[test]
+fn streams_announcing_frames_shorter_than_their_header_are_closed() {
+    let (mut reactor, mut client) = api_channel();
+
+    client.write_all(&[0x00, 0x04, 0x02, 0x1c, 0x00, 0x02]).unwrap();
+    let dispatched = turn(&mut reactor, is_closed);
+    assert_eq!(received(&dispatched), vec![Bytes::from_static(&[0x00, 0x04, 0x02, 0x1c])]);
+    assert!(dispatched.iter().any(is_closed));
+    assert!(reactor.streams().is_empty());
+}
+
+#[test]
+fn frames_cut_off_by_the_client_closing_are_dropped() {
+    let (mut reactor, mut client) = api_channel();
+
+    client.write_all(&[0x00, 0x08, 0x02, 0x1c]).unwrap();
+    client.shutdown(Shutdown::Both).unwrap();
+    let dispatched = turn(&mut reactor, is_closed);
+    assert!(received(&dispatched).is_empty());
+    assert!(dispatched.iter().any(is_closed));
+}
+
+fn accept(reactor: &mut Reactor) -> Token {
+    let dispatched = turn(reactor, |dispatch| match *dispatch {
+        Dispatch::Accepted(_) => true,
+        _ => false
+    });
+    dispatched.iter()
+        .filter_map(|dispatch| match *dispatch {
+            Dispatch::Accepted(client) => Some(client),
+            _ => None
+        })
+        .next().expect("client wasn't accepted")
+}
+
+#[test]
+fn api_messages_only_go_to_the_client_they_are_meant_for() {
+    let (mut reactor, mut first) = api_channel();
+    let client = accept(&mut reactor);
+    let mut second = TcpStream::connect(first.peer_addr().unwrap()).unwrap();
+    accept(&mut reactor);
+
+    let query = || Message::Rps(Rps::Query(RpsQuery {}));
+    write_api_message(&mut reactor, StreamType::API(Some(client), query())).unwrap();
+    // Messages meant for no client in particular aren't broadcast
+    assert!(write_api_message(&mut reactor, StreamType::API(None, query())).is_err());
+
+    let mut buffer = [0; 4];
+    first.read_exact(&mut buffer).unwrap();
+    second.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(second.read(&mut buffer).is_err());
+}
+
+fn udp_socket() -> net::UdpSocket {
+    let socket = net::UdpSocket::bind(peer(0)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    socket
+}
+
+fn knock() -> Datagram {
+    Datagram { receiver_tunnel_id: 0, sender_tunnel_id: 3, message: P2PMessage::new(P2P::Knock) }
+}
+
+/** The next segment arriving at the socket - none if nothing arrives in time **/
+fn next_segment(socket: &net::UdpSocket) -> Option<Segment> {
+    let mut buffer = [0; 1024];
+    socket.recv_from(&mut buffer).ok()
+        .map(|(length, _)| Segment::decode(Bytes::from(&buffer[..length])).unwrap())
+}
+
+#[test]
+fn unacknowledged_control_messages_are_retransmitted_with_backoff_until_given_up() {
+    let transport = Transport::new(udp_socket());
+    let receiver = udp_socket();
+    let sent = Instant::now();
+    transport.send(receiver.local_addr().unwrap(), knock()).unwrap();
+    assert_eq!(next_segment(&receiver).map(|segment| match segment {
+        Segment::Reliable(_, sequence, datagram) => (sequence, datagram),
+        segment => panic!("expected a reliable segment, got {:?}", segment)
+    }), Some((0, knock())));
+
+    // Every retransmission doubles the timeout up to 4s, the sixth transmission is the last one
+    let after = |millis| sent + Duration::from_millis(millis);
+    for &(millis, retransmitted) in &[(200, false), (300, true), (700, false), (800, true), (1800, true),
+        (3800, true), (7700, false), (7800, true), (11800, false), (60000, false)] {
+
+        transport.retransmit_due(after(millis)).unwrap();
+        assert_eq!(next_segment(&receiver).is_some(), retransmitted, "after {}ms", millis);
+    }
+}
+
+#[test]
+fn acknowledged_control_messages_are_not_retransmitted() {
+    let (sender, receiver) = (Transport::new(udp_socket()), Transport::new(udp_socket()));
+    let (sender_address, receiver_address) =
+        (sender.socket().local_addr().unwrap(), receiver.socket().local_addr().unwrap());
+    let sent = Instant::now();
+    sender.send(receiver_address, knock()).unwrap();
+
+    let segment = next_segment(receiver.socket()).unwrap();
+    let epoch = match segment {
+        Segment::Reliable(epoch, _, _) => epoch,
+        segment => panic!("expected a reliable segment, got {:?}", segment)
+    };
+    // Acks from before a restart of ours don't count
+    assert_eq!(sender.receive(receiver_address, Segment::Ack(epoch.wrapping_add(1), 0)).unwrap(), None);
+    sender.retransmit_due(sent + Duration::from_millis(300)).unwrap();
+    assert!(next_segment(receiver.socket()).is_some());
+
+    assert_eq!(receiver.receive(sender_address, segment).unwrap(), Some(knock()));
+    let ack = next_segment(sender.socket()).unwrap();
+    assert_eq!(ack, Segment::Ack(epoch, 0));
+    assert_eq!(sender.receive(receiver_address, ack).unwrap(), None);
+
+    sender.retransmit_due(sent + Duration::from_secs(60)).unwrap();
+    assert!(next_segment(receiver.socket()).is_none());
+}
+
+#[test]
+fn duplicates_are_acknowledged_again_but_only_delivered_once_per_epoch() {
+    let transport = Transport::new(udp_socket());
+    let sender = udp_socket();
+    let source = sender.local_addr().unwrap();
+
+    for &(epoch, delivered) in &[(1, true), (1, false), (2, true), (2, false)] {
+        let mut bytes = BytesMut::with_capacity(64);
+        Segment::Reliable(epoch, 0, knock()).encode(&mut bytes).unwrap();
+
+        let datagram = transport.receive(source, Segment::decode(bytes.freeze()).unwrap()).unwrap();
+        assert_eq!(datagram.is_some(), delivered, "epoch {}", epoch);
+        assert_eq!(next_segment(&sender), Some(Segment::Ack(epoch, 0)));
+    }
+}
+
+fn p2p(message_type: P2P) -> Datagram {
+    Datagram { receiver_tunnel_id: 1, sender_tunnel_id: 2, message: P2PMessage::new(message_type) }
+}
+
+#[test]
+fn a_busy_core_drops_data_and_keeps_control_messages_for_later() {
+    let (tx, rx) = mpsc::sync_channel(1);
+    let mut backlog = Backlog::new(1);
+    backlog.hand_to_core(&tx, peer(1), p2p(P2P::Data)).unwrap();
+    backlog.hand_to_core(&tx, peer(1), p2p(P2P::Data)).unwrap();
+    backlog.hand_to_core(&tx, peer(1), p2p(P2P::Destroy)).unwrap();
+    // A full backlog loses control messages as well
+    backlog.hand_to_core(&tx, peer(1), p2p(P2P::Handshake)).unwrap();
+
+    let received = |rx: &mpsc::Receiver<StreamType>| match rx.try_recv() {
+        Ok(StreamType::P2P(_, datagram)) => Some(datagram.message.message_type),
+        _ => None
+    };
+    assert_eq!(received(&rx), Some(P2P::Data));
+    assert_eq!(received(&rx), None);
+
+    backlog.flush(&tx).unwrap();
+    assert_eq!(received(&rx), Some(P2P::Destroy));
+    assert_eq!(received(&rx), None);
+}
@@ -0,0 +1,196 @@
+// This module is responsible for carrying P2P messages over the node's single udp socket
+use bytes::{Bytes, BytesMut};
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use errors::*;
+use messages::WireFormat;
+use messages::p2p::{P2P, Datagram, Segment};
+
+use super::link::Links;
+
+// Retransmission timeout of a control message, doubled on every retransmission
+const INITIAL_RETRANSMIT_TIMEOUT: u64 = 250;
+const MAX_RETRANSMIT_TIMEOUT: u64 = 4000;
+// Control messages which weren't acknowledged after this many transmissions are given up on
+const MAX_TRANSMISSIONS: u8 = 6;
+
+struct Pending {
+    bytes: BytesMut,
+    message_type: &'static str,
+    transmissions: u8,
+    timeout: Duration,
+    retransmit_at: Instant
+}
+
+struct State {
+    epoch: u32,
+    next_sequence: u32,
+    pending: HashMap<(SocketAddr, u32), Pending>,
+    links: Links
+}
+
+/**
+    The node's udp socket shared between the P2P listener and all state machines
+    Tunnel data is sent as is for low latency, control messages are sent reliably:
+    every one carries a sequence number and is retransmitted with backoff until the peer acknowledges it
+    The P2P listener feeds received segments through `receive`, which acknowledges and drops duplicates
+    Tunnels to the same neighbour share its link, which is reaped once it has been idle for a while.
+    Links are created by attaching a tunnel and are keyed by address only - they aren't authenticated
+**/
+#[derive(Clone)]
+pub struct Transport {
+    socket: Arc<net::UdpSocket>,
+    state: Arc<Mutex<State>>
+}
+impl Transport {
+    pub fn new(socket: net::UdpSocket) -> Transport {
+        Transport {
+            socket: Arc::new(socket),
+            state: Arc::new(Mutex::new(State {
+                // Randomly keyed, so it differs between runs
+                epoch: RandomState::new().build_hasher().finish() as u32,
+                next_sequence: 0,
+                pending: HashMap::new(),
+                links: Links::new()
+            }))
+        }
+    }
+
+    pub fn socket(&self) -> &net::UdpSocket {
+        &self.socket
+    }
+
+    fn lock(&self) -> Result<::std::sync::MutexGuard<State>> {
+        self.state.lock().map_err(|_| "transport state was poisoned".into())
+    }
+
+    fn transmit(&self, peer: SocketAddr, bytes: &[u8]) -> Result<()> {
+        self.socket.send_to(bytes, &peer)
+            .chain_err(|| ErrorKind::Io(format!("sending datagram to {}", peer)))?;
+        Ok(())
+    }
+
+    /** Runs the tunnel over the link to the peer - see `Links::attach` **/
+    pub fn attach(&self, peer: SocketAddr, hostkey: Option<&Bytes>) -> Result<u64> {
+        self.lock()?.links.attach(peer, hostkey)
+    }
+
+    pub fn detach(&self, peer: SocketAddr, attachment: u64) {
+        if let Ok(mut state) = self.lock() {
+            state.links.detach(peer, attachment);
+        }
+    }
+
+    /** Sends the datagram to the peer - everything but tunnel data is retransmitted until acknowledged **/
+    pub fn send(&self, peer: SocketAddr, datagram: Datagram) -> Result<()> {
+        let mut bytes = BytesMut::with_capacity(1024);
+        let mut state = self.lock()?;
+        state.links.touch(peer);
+
+        if datagram.message.message_type == P2P::Data {
+            drop(state);
+            Segment::Unreliable(datagram).encode(&mut bytes)?;
+            return self.transmit(peer, &bytes);
+        }
+
+        let message_type = datagram.message.message_type.name();
+        let sequence = state.next_sequence;
+        state.next_sequence = sequence.wrapping_add(1);
+
+        Segment::Reliable(state.epoch, sequence, datagram).encode(&mut bytes)?;
+        // Lost first transmissions are taken care of by retransmission
+        let _ = self.transmit(peer, &bytes);
+
+        let timeout = Duration::from_millis(INITIAL_RETRANSMIT_TIMEOUT);
+        state.pending.insert((peer, sequence), Pending {
+            bytes: bytes,
+            message_type: message_type,
+            transmissions: 1,
+            timeout: timeout,
+            retransmit_at: Instant::now() + timeout
+        });
+        Ok(())
+    }
+
+    /** Returns the datagram carried by the segment unless it is an acknowledgement or a duplicate **/
+    pub fn receive(&self, source: SocketAddr, segment: Segment) -> Result<Option<Datagram>> {
+        // Only links of attached tunnels are kept alive - segments alone never create one
+        self.lock()?.links.touch(source);
+
+        match segment {
+            Segment::Unreliable(datagram) => Ok(Some(datagram)),
+            Segment::Ack(epoch, sequence) => {
+                let mut state = self.lock()?;
+                // Acks for what we sent before a restart are stale
+                if epoch == state.epoch {
+                    state.pending.remove(&(source, sequence));
+                }
+                Ok(None)
+            },
+            Segment::Reliable(epoch, sequence, datagram) => {
+                // Duplicates are acknowledged again as the previous acknowledgement might have been lost
+                let mut bytes = BytesMut::with_capacity(12);
+                Segment::Ack(epoch, sequence).encode(&mut bytes)?;
+                self.transmit(source, &bytes)?;
+
+                if self.lock()?.links.delivered(source, epoch, sequence) {
+                    return Ok(None);
+                }
+                Ok(Some(datagram))
+            }
+        }
+    }
+
+    /** Retransmits unacknowledged control messages which are due - called by the P2P listener every turn **/
+    pub fn retransmit(&self) -> Result<()> {
+        self.retransmit_due(Instant::now())
+    }
+
+    /** Retransmits what is due by `now` - lets the backoff be checked without waiting for it **/
+    pub fn retransmit_due(&self, now: Instant) -> Result<()> {
+        let mut state = self.lock()?;
+
+        let mut abandoned = vec![];
+        for (&(peer, sequence), pending) in &mut state.pending {
+            if pending.retransmit_at > now {
+                continue;
+            }
+            if pending.transmissions >= MAX_TRANSMISSIONS {
+                abandoned.push((peer, sequence));
+                continue;
+            }
+
+            let _ = self.transmit(peer, &pending.bytes);
+            pending.transmissions += 1;
+            pending.timeout = ::std::cmp::min(pending.timeout * 2, Duration::from_millis(MAX_RETRANSMIT_TIMEOUT));
+            pending.retransmit_at = now + pending.timeout;
+        }
+
+        for key in abandoned {
+            if let Some(pending) = state.pending.remove(&key) {
+                note!(format!("{} to {} was never acknowledged - giving up", pending.message_type, key.0));
+            }
+        }
+        Ok(())
+    }
+
+    /** Forgets links which were idle for longer than the timeout - called by the P2P listener every turn **/
+    pub fn reap(&self, idle: Duration) -> Result<()> {
+        let mut state = self.lock()?;
+        let state = &mut *state;
+
+        let pending = &state.pending;
+        // Links still retransmitting to the peer aren't idle yet
+        for peer in state.links.reap(idle, |peer| pending.keys().any(|&(pending_peer, _)| pending_peer == peer)) {
+            note!(format!("link to {} has been idle for too long - reaping", peer));
+        }
+        Ok(())
+    }
+}
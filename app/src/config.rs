@@ -3,45 +3,240 @@ extern crate ini;
 use self::ini::Ini;
 use self::ini::ini::Properties;
 
+use log::LogLevelFilter;
+
 use errors::*;
+use logger;
+use messages::p2p::{Padding, MIN_CELL_SIZE};
+use core::MIN_SEND_WINDOW;
 
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct Config {
+    pub file_path: String,
     pub hostkey_path: String,
     pub api_socket: SocketAddr,
     pub p2p_socket: SocketAddr,
-    pub min_hop_count: u8
+    pub min_hop_count: u8,
+    pub reply_timeout: Duration,
+    pub build_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub log_level: LogLevelFilter,
+    pub log_target: logger::Target,
+    pub log_file: String,
+    pub log_format: logger::Format,
+    pub trace_tunnels: Vec<u32>,
+    pub cover_traffic: bool,
+    pub cell_size: u16,
+    pub padding: Vec<Padding>,
+    pub listener_failure_budget: u32,
+    pub channel_capacity: usize,
+    pub send_window: u32,
+    pub relay_tunnel_rate: u64,
+    pub relay_total_rate: u64,
+    pub max_relayed_tunnels: usize,
+    pub link_idle_timeout: Duration,
+    pub keepalive_interval: Duration,
+    pub keepalive_misses: u32,
+    pub rebuild_dead_tunnels: bool
+}
+
+/** Outcome of re-reading the config file while the app is running **/
+pub struct Reload {
+    pub config: Config,
+    pub applied: Vec<&'static str>,
+    pub requires_restart: Vec<&'static str>
+}
+
+impl Config {
+    /** Makes sure the values are usable before they reach the rest of the app **/
+    pub fn validate(&self) -> Result<()> {
+        if self.min_hop_count < 1 {
+            bail!(invalid("min_hop_count", "has to be at least 1"));
+        }
+        if self.reply_timeout == Duration::from_millis(0) {
+            bail!(invalid("reply_timeout", "has to be greater than 0"));
+        }
+        if self.build_timeout < self.reply_timeout {
+            bail!(invalid("build_timeout", "can't be shorter than [reply_timeout]"));
+        }
+        if self.cell_size < MIN_CELL_SIZE {
+            bail!(invalid("cell_size", &format!("has to be at least {}", MIN_CELL_SIZE)));
+        }
+        if self.padding.is_empty() {
+            bail!(invalid("padding", "has to list at least one scheme"));
+        }
+        if self.channel_capacity < 1 {
+            bail!(invalid("channel_capacity", "has to be at least 1"));
+        }
+        if self.send_window < MIN_SEND_WINDOW {
+            bail!(invalid("send_window", &format!("has to be at least {}", MIN_SEND_WINDOW)));
+        }
+        // A bucket smaller than a cell would never let one through
+        if self.relay_tunnel_rate != 0 && self.relay_tunnel_rate < u64::from(self.cell_size) {
+            bail!(invalid("relay_tunnel_rate", "has to be 0 or at least [cell_size]"));
+        }
+        if self.relay_total_rate != 0 && self.relay_total_rate < u64::from(self.cell_size) {
+            bail!(invalid("relay_total_rate", "has to be 0 or at least [cell_size]"));
+        }
+        if self.keepalive_interval == Duration::from_millis(0) {
+            bail!(invalid("keepalive_interval", "has to be greater than 0"));
+        }
+        if self.keepalive_misses < 1 {
+            bail!(invalid("keepalive_misses", "has to be at least 1"));
+        }
+        Ok(())
+    }
+
+    /**
+        Re-reads the config file this configuration was created from
+        Properties which can't change at runtime keep their current value
+    **/
+    pub fn reload(&self) -> Result<Reload> {
+        let read = read_config_file(self.file_path.clone())?;
+
+        let mut applied = vec![];
+        let mut requires_restart = vec![];
+
+        if read.min_hop_count != self.min_hop_count { applied.push("min_hop_count") }
+        if read.reply_timeout != self.reply_timeout { applied.push("reply_timeout") }
+        if read.build_timeout != self.build_timeout { applied.push("build_timeout") }
+        if read.shutdown_timeout != self.shutdown_timeout { applied.push("shutdown_timeout") }
+        if read.log_level != self.log_level { applied.push("log_level") }
+        if read.log_target != self.log_target { applied.push("log_target") }
+        if read.log_file != self.log_file { applied.push("log_file") }
+        if read.log_format != self.log_format { applied.push("log_format") }
+        if read.trace_tunnels != self.trace_tunnels { applied.push("trace_tunnels") }
+        if read.cover_traffic != self.cover_traffic { applied.push("cover_traffic") }
+        if read.cell_size != self.cell_size { applied.push("cell_size") }
+        if read.padding != self.padding { applied.push("padding") }
+        if read.send_window != self.send_window { applied.push("send_window") }
+        if read.relay_tunnel_rate != self.relay_tunnel_rate { applied.push("relay_tunnel_rate") }
+        if read.keepalive_interval != self.keepalive_interval { applied.push("keepalive_interval") }
+        if read.keepalive_misses != self.keepalive_misses { applied.push("keepalive_misses") }
+        if read.rebuild_dead_tunnels != self.rebuild_dead_tunnels { applied.push("rebuild_dead_tunnels") }
+
+        if read.hostkey_path != self.hostkey_path { requires_restart.push("hostkey") }
+        if read.api_socket != self.api_socket { requires_restart.push("api_addr") }
+        if read.p2p_socket != self.p2p_socket { requires_restart.push("p2p_port") }
+        if read.listener_failure_budget != self.listener_failure_budget {
+            requires_restart.push("listener_failure_budget")
+        }
+        if read.channel_capacity != self.channel_capacity { requires_restart.push("channel_capacity") }
+        if read.relay_total_rate != self.relay_total_rate { requires_restart.push("relay_total_rate") }
+        if read.max_relayed_tunnels != self.max_relayed_tunnels { requires_restart.push("max_relayed_tunnels") }
+        if read.link_idle_timeout != self.link_idle_timeout { requires_restart.push("link_idle_timeout") }
+
+        Ok(Reload {
+            config: Config {
+                file_path: self.file_path.clone(),
+                hostkey_path: self.hostkey_path.clone(),
+                api_socket: self.api_socket,
+                p2p_socket: self.p2p_socket,
+                listener_failure_budget: self.listener_failure_budget,
+                channel_capacity: self.channel_capacity,
+                relay_total_rate: self.relay_total_rate,
+                max_relayed_tunnels: self.max_relayed_tunnels,
+                link_idle_timeout: self.link_idle_timeout,
+                ..read
+            },
+            applied: applied,
+            requires_restart: requires_restart
+        })
+    }
+}
+
+fn invalid(property: &str, reason: &str) -> ErrorKind {
+    ErrorKind::Config(property.to_string(), reason.to_string())
+}
+
+fn unparsable(property: &str) -> ErrorKind {
+    invalid(property, "property failed to parse")
 }
 
 #[allow(or_fun_call)]
 fn read_property(section: &Properties, property: &'static str) -> Result<String> {
     Ok(section.get(property)
-        .ok_or(Error::from(format!("[{}] property not found in config file", property).to_string()))?
+        .ok_or(Error::from(invalid(property, "property not found in config file")))?
         .to_string())
 }
 
+fn read_optional_property(section: &Properties, property: &'static str, default: &'static str) -> String {
+    section.get(property).map_or(default.to_string(), |value| value.to_string())
+}
+
+fn read_duration_property(section: &Properties, property: &'static str, default: &'static str)
+    -> Result<Duration> {
+    Ok(Duration::from_millis(read_optional_property(section, property, default).parse()
+        .chain_err(|| unparsable(property))?))
+}
+
 /** Parses the config file and creates an object to be used across the app **/
 #[allow(or_fun_call)]
 pub fn read_config_file(config_file_path: String) -> Result<Config> {
-    let config_file = Ini::load_from_file(config_file_path)
-        .chain_err(|| "Config file not found")?;
+    let config_file = Ini::load_from_file(config_file_path.clone())
+        .chain_err(|| invalid("file", "config file not found"))?;
 
     let onion_section = config_file.section(Some("onion".to_owned()))
-        .ok_or(Error::from("[onion] section not found in config file"))?;
+        .ok_or(Error::from(invalid("onion", "section not found in config file")))?;
 
     let config = Config {
+        file_path: config_file_path,
         hostkey_path: read_property(onion_section, "hostkey")?,
         api_socket: SocketAddr::from_str(&read_property(onion_section, "api_addr")?)
-            .chain_err(|| "[api_addr] property failed to parse")?,
+            .chain_err(|| unparsable("api_addr"))?,
         p2p_socket: SocketAddr::from_str(&format!("0.0.0.0:{}",
             read_property(onion_section, "p2p_port")?))
-                .chain_err(|| "[p2p_port] property failed to parse")?,
+                .chain_err(|| unparsable("p2p_port"))?,
         min_hop_count: read_property(onion_section, "min_hop_count")?.parse()
-            .chain_err(|| "[min_hop_count] property failed to parse")?
+            .chain_err(|| unparsable("min_hop_count"))?,
+        reply_timeout: read_duration_property(onion_section, "reply_timeout", "5000")?,
+        build_timeout: read_duration_property(onion_section, "build_timeout", "30000")?,
+        shutdown_timeout: read_duration_property(onion_section, "shutdown_timeout", "5000")?,
+        log_level: LogLevelFilter::from_str(&read_optional_property(onion_section, "log_level", "info"))
+            .map_err(|_| Error::from(unparsable("log_level")))?,
+        log_target: read_optional_property(onion_section, "log_target", "terminal").parse()
+            .chain_err(|| unparsable("log_target"))?,
+        log_file: read_optional_property(onion_section, "log_file", "garlic.log"),
+        log_format: read_optional_property(onion_section, "log_format", "plain").parse()
+            .chain_err(|| unparsable("log_format"))?,
+        trace_tunnels: read_optional_property(onion_section, "trace_tunnels", "").split(',')
+            .map(|tunnel_id| tunnel_id.trim()).filter(|tunnel_id| !tunnel_id.is_empty())
+            .map(|tunnel_id| tunnel_id.parse())
+            .collect::<::std::result::Result<_, _>>()
+            .chain_err(|| unparsable("trace_tunnels"))?,
+        cover_traffic: read_optional_property(onion_section, "cover_traffic", "false").parse()
+            .chain_err(|| unparsable("cover_traffic"))?,
+        cell_size: read_optional_property(onion_section, "cell_size", "512").parse()
+            .chain_err(|| unparsable("cell_size"))?,
+        padding: read_optional_property(onion_section, "padding", "fixed_cell,none").split(',')
+            .map(|padding| padding.parse())
+            .collect::<Result<_>>()
+            .chain_err(|| unparsable("padding"))?,
+        listener_failure_budget: read_optional_property(onion_section, "listener_failure_budget", "5").parse()
+            .chain_err(|| unparsable("listener_failure_budget"))?,
+        channel_capacity: read_optional_property(onion_section, "channel_capacity", "1024").parse()
+            .chain_err(|| unparsable("channel_capacity"))?,
+        send_window: read_optional_property(onion_section, "send_window", "256").parse()
+            .chain_err(|| unparsable("send_window"))?,
+        relay_tunnel_rate: read_optional_property(onion_section, "relay_tunnel_rate", "0").parse()
+            .chain_err(|| unparsable("relay_tunnel_rate"))?,
+        relay_total_rate: read_optional_property(onion_section, "relay_total_rate", "0").parse()
+            .chain_err(|| unparsable("relay_total_rate"))?,
+        max_relayed_tunnels: read_optional_property(onion_section, "max_relayed_tunnels", "64").parse()
+            .chain_err(|| unparsable("max_relayed_tunnels"))?,
+        link_idle_timeout: read_duration_property(onion_section, "link_idle_timeout", "60000")?,
+        keepalive_interval: read_duration_property(onion_section, "keepalive_interval", "5000")?,
+        keepalive_misses: read_optional_property(onion_section, "keepalive_misses", "3").parse()
+            .chain_err(|| unparsable("keepalive_misses"))?,
+        rebuild_dead_tunnels: read_optional_property(onion_section, "rebuild_dead_tunnels", "false").parse()
+            .chain_err(|| unparsable("rebuild_dead_tunnels"))?
     };
 
+    config.validate()?;
+
     Ok(config)
 }
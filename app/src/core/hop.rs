@@ -0,0 +1,380 @@
+// This module is responsible for our end of tunnels other nodes build through us
+use bytes::{Bytes, BytesMut};
+
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+use errors::*;
+use messages::{MessageId, WireFormat};
+use messages::cell::{Cell, DigestKey};
+use messages::Message::*;
+use messages::onion::*;
+use messages::onion::Onion;
+use messages::onion::Onion::*;
+use messages::auth::*;
+use messages::auth::Auth::*;
+use messages::p2p::{P2P, P2PMessage, Datagram, Relay, Answer};
+use config;
+
+use super::{Communication, Connection, StreamType, NEXT_REQUEST_ID};
+use super::{breach, p2p_breach, capabilities, encrypt_layer, decrypt_layer, auth_decrypt};
+use super::keepalive::Keepalive;
+use super::replay::{SequenceNumbers, ReplayWindow};
+use super::stream;
+use super::stream::{Streams, TUNNEL_STREAM};
+use super::window::{SendWindow, ReceiveWindow};
+use super::limit::{TokenBucket, RelaySlot};
+
+/**
+    Our end of a tunnel - the previous hop is whoever knocked on us, the next one is only known
+    once the initiator extends the tunnel through us
+    Recognised cells carry either data for us as the tunnel's destination or a message to relay to the next hop,
+    all others are passed on. Whatever we send back is wrapped in our own layer
+**/
+struct Hop {
+    tunnel_id: u32,
+    session_id: u16,
+    digest_key: DigestKey,
+    previous: Connection,
+    next: Option<Connection>,
+    forward: ReplayWindow,
+    backward: SequenceNumbers,
+    // Cells which failed our digest - anyone can send datagrams which look like the previous hop's
+    forged: u64,
+    streams: Streams,
+    // Data we send as the tunnel's destination and the cells received from the initiator
+    window: SendWindow,
+    received: ReceiveWindow,
+    // Relayed traffic in both directions counts towards the tunnel's limit as well as the total one
+    limit: TokenBucket,
+    throttled: u64,
+    _slot: RelaySlot,
+    // The initiator's probes reach every hop, so a silent previous hop means the tunnel is gone
+    keepalive: Keepalive,
+    // Whether the API was told about the tunnel yet
+    incomming: bool
+}
+impl Hop {
+    /** Wraps the payload in our layer and sends it towards the initiator **/
+    fn send_back(&mut self, recognised: bool, stream_id: u16, payload: Bytes, comm: &Communication) -> Result<()> {
+        let data = encrypt_layer(self.session_id, &self.digest_key, Cell {
+            recognised: recognised,
+            stream_id: stream_id,
+            sequence: self.backward.next()?,
+            payload: payload
+        }, comm)?;
+
+        self.previous.send(P2PMessage::carrying(P2P::Data, &data))
+    }
+
+    /** Messages of our own are the only ones sent back in a recognised layer **/
+    fn reply(&mut self, stream_id: u16, message: P2PMessage, comm: &Communication) -> Result<()> {
+        let mut payload = BytesMut::with_capacity(64);
+        message.encode(&mut payload)?;
+        self.send_back(true, stream_id, payload.freeze(), comm)
+    }
+
+    /** Sends queued data back as far as the send window allows **/
+    fn flush(&mut self, comm: &Communication) -> Result<()> {
+        while let Some((stream_id, message)) = self.window.next(self.backward.used()) {
+            self.reply(stream_id, message, comm)?;
+        }
+        Ok(())
+    }
+
+    /** Requests of the API client for the initiator - data has to fit into the send window **/
+    fn outgoing(&mut self, request: Onion, comm: &Communication) -> Result<()> {
+        let (stream_id, message) = match self.streams.outgoing(self.tunnel_id, request, comm)? {
+            Some(cell) => cell,
+            None => return Ok(())
+        };
+        if message.message_type != P2P::Data {
+            return self.reply(stream_id, message, comm);
+        }
+
+        let overflow = self.streams.overflow(stream_id);
+        if !self.window.queue(stream_id, message, overflow) {
+            let request_type = if stream_id == TUNNEL_STREAM {
+                MessageId::OnionTunnelData
+            } else {
+                MessageId::OnionStreamData
+            };
+            stream::reject(self.tunnel_id, request_type, "tunnel is congested", comm);
+        }
+        self.flush(comm)
+    }
+
+    /**
+        Hands a message of the initiator to the API - which learns about the tunnel with the first one
+        Credit reopens our send window instead
+    **/
+    fn deliver(&mut self, sequence: u32, stream_id: u16, message: P2PMessage, comm: &Communication) -> Result<()> {
+        // Every numbered cell takes up room in the initiator's send window - credit included
+        if let Some(sequence) = self.received.receive(sequence) {
+            self.reply(TUNNEL_STREAM, P2PMessage::credit(sequence)?, comm)?;
+        }
+        if message.message_type == P2P::Credit {
+            self.window.credit(message.credited()?);
+            return self.flush(comm);
+        }
+        // Probes are answered by the destination - the API doesn't learn about tunnels which only carry them
+        if message.message_type == P2P::Keepalive {
+            return self.reply(TUNNEL_STREAM, P2PMessage::new(P2P::Keepalive), comm);
+        }
+
+        if !self.incomming {
+            comm.send(Onion(TunnelIncomming(OnionTunnelIncomming(OnionTunnelID {
+                tunnel_id: self.tunnel_id
+            }))));
+            self.incomming = true;
+        }
+
+        self.streams.incomming(self.tunnel_id, stream_id, message, comm)
+    }
+
+    /** Cells over the limit are dropped like any other lost datagram **/
+    fn within_limits(&mut self, bytes: usize, comm: &Communication) -> bool {
+        if comm.relays.take(&mut self.limit, bytes as u64) {
+            return true;
+        }
+        self.throttled += 1;
+        false
+    }
+
+    /** Knocks start over with a new next hop - the initiator retries hops which failed to connect **/
+    fn relay(&mut self, relay: Relay, comm: &Communication) -> Result<()> {
+        if relay.message.message_type == P2P::Knock {
+            // The abandoned hop might be reached over the same link
+            if let Some(mut abandoned) = self.next.take() {
+                let _ = abandoned.send(P2PMessage::new(P2P::Destroy));
+            }
+            self.next = Some(Connection::open(self.tunnel_id, 0, SocketAddr::new(relay.ip_addr, relay.port), None,
+                &comm.transport)?);
+        }
+
+        match self.next {
+            Some(ref mut next) => next.send(relay.message),
+            None => bail!(p2p_breach("P2PKnock to extend the tunnel", &relay.message))
+        }
+    }
+
+    /** Returns whether the tunnel is still up **/
+    fn from_previous(&mut self, datagram: Datagram, comm: &Communication) -> Result<bool> {
+        match datagram.message.message_type {
+            P2P::Data => (),
+            P2P::Destroy => return Ok(false),
+            _ => bail!(p2p_breach("P2PData or P2PDestroy", &datagram.message))
+        }
+
+        let cell = match decrypt_layer(self.session_id, &self.digest_key, datagram.message.payload()?, comm) {
+            Ok(cell) => cell,
+            Err(::errors::Error(ErrorKind::Tampered(reason), _)) => {
+                note!(format!("dropping cell which failed its digest - {}", reason));
+                self.forged += 1;
+                return Ok(true);
+            },
+            Err(e) => return Err(e)
+        };
+        if !self.forward.accept(cell.sequence) {
+            note!(format!("dropping replayed cell {}", cell.sequence));
+            return Ok(true);
+        }
+        self.keepalive.heard();
+
+        if !cell.recognised {
+            if self.next.is_none() {
+                bail!(ErrorKind::Tampered("cell to relay but the tunnel ends here".to_string()));
+            }
+            if self.within_limits(cell.payload.len(), comm) {
+                if let Some(ref mut next) = self.next {
+                    next.send(P2PMessage::carrying(P2P::Data, &cell.payload))?;
+                }
+            }
+            return Ok(true);
+        }
+
+        let message = P2PMessage::decode(cell.payload)?;
+        match message.message_type {
+            P2P::Forward => self.relay(message.relay()?, comm)?,
+            _ => self.deliver(cell.sequence, cell.stream_id, message, comm)?
+        }
+        Ok(true)
+    }
+
+    /** Returns whether the tunnel is still up **/
+    fn from_next(&mut self, datagram: Datagram, comm: &Communication) -> Result<bool> {
+        match datagram.message.message_type {
+            P2P::WhosThere | P2P::Handshake => self.reply(TUNNEL_STREAM, datagram.message, comm)?,
+            P2P::Data => {
+                let payload = datagram.message.payload()?;
+                if self.within_limits(payload.len(), comm) {
+                    self.send_back(false, TUNNEL_STREAM, payload, comm)?;
+                }
+            },
+            P2P::Destroy => return Ok(false),
+            _ => bail!(p2p_breach("P2PWhosThere, P2PHandshake, P2PData or P2PDestroy", &datagram.message))
+        }
+        Ok(true)
+    }
+
+    /** The API client only knows about the tunnel if we are its destination **/
+    fn report_failure(&self, comm: &Communication) {
+        if self.incomming {
+            comm.send(Onion(::messages::onion::Onion::Error(OnionError {
+                tunnel_id: self.tunnel_id,
+                request_type: MessageId::OnionTunnelData as u16
+            })));
+        }
+    }
+
+    /** Tells both neighbours to forget about the tunnel **/
+    fn teardown(&mut self, comm: &Communication) {
+        // Either neighbour might be the one which tore the tunnel down already
+        let _ = self.previous.send(P2PMessage::new(P2P::Destroy));
+        if let Some(ref mut next) = self.next {
+            let _ = next.send(P2PMessage::new(P2P::Destroy));
+        }
+
+        if self.forward.dropped() > 0 {
+            note!(format!("dropped {} replayed cell(s) during the tunnel's lifetime", self.forward.dropped()));
+        }
+        if self.window.dropped() > 0 {
+            note!(format!("dropped {} voice cell(s) while the tunnel was congested", self.window.dropped()));
+        }
+        if self.forged > 0 {
+            note!(format!("dropped {} cell(s) which failed their digest", self.forged));
+        }
+        if self.throttled > 0 {
+            note!(format!("dropped {} relayed cell(s) over the rate limit", self.throttled));
+        }
+        comm.send(Auth(SessionClose(AuthSessionClose {
+            session_id: self.session_id
+        })));
+    }
+}
+
+/** Negotiates with a knocking peer - malformed knocks are rejected rather than dropped **/
+fn answer_knock(message: &P2PMessage, conf: &config::Config) -> Answer {
+    match message.capabilities() {
+        Ok(knock) => capabilities(conf).answer(&knock),
+        Err(e) => Answer::Rejected(format!("{}", e))
+    }
+}
+
+/**
+    Answers the initiator's half of the Auth handshake relayed by the previous hop
+    The initiator follows up with the key our layers are digested with, encrypted with the new session
+**/
+fn handshake(previous: &mut Connection, comm: &Communication) -> Result<(u16, DigestKey)> {
+    let hs1 = receive_handshake(previous, comm)?;
+    comm.send(Auth(SessionIncommingHS1(AuthSessionHS1Response {
+        request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst) as u32,
+        payload: hs1
+    })));
+
+    let session_id = match comm.receive("AuthSessionHS2")? {
+        Auth(SessionHS2(message)) => {
+            previous.send(P2PMessage::carrying(P2P::Handshake, &message.payload))?;
+            message.session_id
+        },
+        message => bail!(breach("AuthSessionHS2", &message))
+    };
+
+    let digest_key = DigestKey::decode(auth_decrypt(session_id, receive_handshake(previous, comm)?, comm)?)?;
+    previous.send(P2PMessage::new(P2P::Handshake))?;
+    Ok((session_id, digest_key))
+}
+
+fn receive_handshake(previous: &mut Connection, comm: &Communication) -> Result<Bytes> {
+    let (source, datagram) = comm.receive_from_hop("P2PHandshake")?;
+    if !previous.is_from(source, &datagram) || datagram.message.message_type != P2P::Handshake {
+        bail!(p2p_breach("P2PHandshake", &datagram.message));
+    }
+    datagram.message.payload()
+}
+
+pub fn answer_dialogue(source: SocketAddr, knock: &Datagram, conf: &config::Config, comm: &Communication) {
+    let slot = comm.relays.admit();
+    let answer = match slot {
+        Some(_) => answer_knock(&knock.message, conf),
+        None => Answer::Rejected(format!("already relaying the maximum of {} tunnels", comm.relays.max_relayed()))
+    };
+    if let Answer::Rejected(ref reason) = answer {
+        note!(format!("rejecting knock from {} - {}", source, reason));
+    }
+
+    let mut hop = None;
+    trace_labeled_error!("knock could not be answered", {
+        let mut previous = Connection::open(comm.tunnel_id, knock.sender_tunnel_id, source, None, &comm.transport)?;
+        previous.send(P2PMessage::whos_there(&answer)?)?;
+        // A rejected knock frees its slot right away
+        if let (&Answer::Accepted(_), Some(slot)) = (&answer, slot) {
+            let (session_id, digest_key) = handshake(&mut previous, comm)?;
+            hop = Some(Hop {
+                tunnel_id: comm.tunnel_id,
+                session_id: session_id,
+                digest_key: digest_key,
+                previous: previous,
+                next: None,
+                forward: ReplayWindow::new(),
+                backward: SequenceNumbers::new(),
+                forged: 0,
+                streams: Streams::new(),
+                window: SendWindow::new(conf.send_window),
+                received: ReceiveWindow::new(),
+                limit: TokenBucket::new(conf.relay_tunnel_rate),
+                throttled: 0,
+                _slot: slot,
+                keepalive: Keepalive::from_config(conf),
+                incomming: false
+            });
+        }
+    });
+
+    let mut hop = match hop {
+        Some(hop) => hop,
+        None => return
+    };
+
+    trace_labeled_error!("relaying encountered a problem", {
+        loop {
+            let alive = match comm.wait_until(hop.keepalive.dead_at())? {
+                None => true,
+                Some(StreamType::P2P(source, datagram)) => {
+                    let from_next = hop.next.as_mut().map_or(false, |next| next.is_from(source, &datagram));
+                    if hop.previous.is_from(source, &datagram) {
+                        let relayed = hop.from_previous(datagram, comm);
+                        if let Err(::errors::Error(ErrorKind::Tampered(_), _)) = relayed {
+                            hop.report_failure(comm);
+                        }
+                        relayed?
+                    } else if from_next {
+                        hop.from_next(datagram, comm)?
+                    } else {
+                        note!(format!("{} from {} is not part of the tunnel - discarding",
+                            datagram.message.message_type.name(), source));
+                        true
+                    }
+                },
+                Some(StreamType::API(_, Onion(TunnelDestroy(_)))) => false,
+                // Replies of the API client reach the initiator from us as the tunnel's destination
+                Some(StreamType::API(_, Onion(request))) => {
+                    hop.outgoing(request, comm)?;
+                    true
+                },
+                Some(stream) => bail!(ErrorKind::ProtocolBreach("P2P message or OnionTunnelData".to_string(),
+                    stream.name().to_string()))
+            };
+
+            if !alive {
+                break;
+            }
+            if hop.keepalive.is_dead() {
+                hop.report_failure(comm);
+                bail!(ErrorKind::Timeout("keepalive from the previous hop".to_string()));
+            }
+        }
+    });
+
+    hop.teardown(comm);
+}
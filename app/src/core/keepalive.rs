@@ -0,0 +1,71 @@
+// This module is responsible for telling tunnels whose far end went silent apart from idle ones
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use config;
+
+/**
+    When one end of a tunnel last sent and heard something
+    The initiator probes the destination whenever it either sent or heard nothing for an interval,
+    so every hop hears from the initiator at least once per interval and the destination answers every probe
+    Tunnels keep their links busy this way - links without tunnels are reaped instead
+**/
+pub struct Keepalive {
+    interval: Duration,
+    misses: u32,
+    sent: Instant,
+    heard: Instant,
+    probed: Instant
+}
+impl Keepalive {
+    pub fn new(interval: Duration, misses: u32) -> Keepalive {
+        let now = Instant::now();
+        Keepalive {
+            interval: interval,
+            misses: misses,
+            sent: now,
+            heard: now,
+            probed: now
+        }
+    }
+
+    pub fn from_config(conf: &config::Config) -> Keepalive {
+        Keepalive::new(conf.keepalive_interval, conf.keepalive_misses)
+    }
+
+    pub fn sent(&mut self) {
+        self.sent = Instant::now();
+    }
+
+    /** Anything authentic from the far end proves the tunnel is still alive **/
+    pub fn heard(&mut self) {
+        self.heard = Instant::now();
+    }
+
+    pub fn probed(&mut self) {
+        self.probed = Instant::now();
+        self.sent = self.probed;
+    }
+
+    /** Whether a probe should be sent - unanswered probes are repeated once per interval **/
+    pub fn is_due(&self) -> bool {
+        self.sent.elapsed() >= self.interval || (self.heard.elapsed() >= self.interval
+            && self.probed.elapsed() >= self.interval)
+    }
+
+    /** Whether the far end missed too many intervals in a row **/
+    pub fn is_dead(&self) -> bool {
+        self.heard.elapsed() >= self.interval * self.misses
+    }
+
+    /** When the far end counts as dead unless something is heard of it until then **/
+    pub fn dead_at(&self) -> Instant {
+        self.heard + self.interval * self.misses
+    }
+
+    /** When either a probe becomes due or the far end counts as dead **/
+    pub fn deadline(&self) -> Instant {
+        let probe_at = cmp::min(self.sent, cmp::max(self.heard, self.probed)) + self.interval;
+        cmp::min(probe_at, self.dead_at())
+    }
+}
@@ -0,0 +1,111 @@
+// This module is responsible for limiting what other nodes may relay through us
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use config;
+
+/**
+    Lets through up to `rate` bytes per second - bursts of up to a second worth of traffic are fine
+    A rate of 0 means no limit
+**/
+pub struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    refilled: Instant
+}
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            tokens: rate,
+            refilled: Instant::now()
+        }
+    }
+
+    /** Fractions of a token keep accumulating until they add up to a whole one **/
+    fn refill(&mut self) {
+        let elapsed = self.refilled.elapsed();
+        let refill = elapsed.as_secs().saturating_mul(self.rate)
+            .saturating_add(u64::from(elapsed.subsec_nanos()) * self.rate / 1_000_000_000);
+        if refill == 0 {
+            return;
+        }
+
+        self.tokens = cmp::min(self.rate, self.tokens.saturating_add(refill));
+        // Only the time which was turned into tokens is used up - the rest counts towards the next one
+        self.refilled += Duration::new(refill / self.rate, ((refill % self.rate) * 1_000_000_000 / self.rate) as u32);
+    }
+
+    /** Whether the bytes may pass - without using up any tokens **/
+    pub fn allows(&mut self, bytes: u64) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        self.refill();
+        self.tokens >= bytes
+    }
+
+    /** Returns whether the bytes may pass - rejected ones don't use up any tokens **/
+    pub fn take(&mut self, bytes: u64) -> bool {
+        if !self.allows(bytes) {
+            return false;
+        }
+        if self.rate != 0 {
+            self.tokens -= bytes;
+        }
+        true
+    }
+}
+
+/** Frees its tunnel's place among the relayed ones once dropped **/
+pub struct RelaySlot(Arc<AtomicUsize>);
+impl Drop for RelaySlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/**
+    Limits shared by all tunnels other nodes build through us
+    Whether we end up as an intermediate hop or the destination isn't known when being knocked on,
+    so every tunnel answered takes up a slot
+**/
+#[derive(Clone)]
+pub struct Relays {
+    total: Arc<Mutex<TokenBucket>>,
+    relayed: Arc<AtomicUsize>,
+    max_relayed: usize
+}
+impl Relays {
+    pub fn new(conf: &config::Config) -> Relays {
+        Relays {
+            total: Arc::new(Mutex::new(TokenBucket::new(conf.relay_total_rate))),
+            relayed: Arc::new(AtomicUsize::new(0)),
+            max_relayed: conf.max_relayed_tunnels
+        }
+    }
+
+    /** A slot for one more tunnel - none while relaying as many as allowed **/
+    pub fn admit(&self) -> Option<RelaySlot> {
+        if self.relayed.fetch_add(1, Ordering::SeqCst) >= self.max_relayed {
+            self.relayed.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(RelaySlot(self.relayed.clone()))
+    }
+
+    pub fn max_relayed(&self) -> usize {
+        self.max_relayed
+    }
+
+    /**
+        Whether the bytes fit into both the tunnel's own limit and the one all relayed tunnels share
+        They are only charged to either limit if they fit into both
+    **/
+    pub fn take(&self, tunnel: &mut TokenBucket, bytes: u64) -> bool {
+        let mut total = self.total.lock().unwrap();
+        total.allows(bytes) && tunnel.take(bytes) && total.take(bytes)
+    }
+}
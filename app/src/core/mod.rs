@@ -0,0 +1,1146 @@
+use mio::tcp::{TcpStream, TcpListener};
+use mio::{Poll, Token, Ready, PollOpt, Events};
+use bytes::{Bytes, BytesMut};
+
+use std::net;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::thread;
+use std::thread::{JoinHandle};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
+
+use errors::*;
+use brunch::Transport;
+use messages;
+use messages::{Message, MessageId, WireFormat};
+use messages::cell::{Cell, DigestKey};
+use messages::Message::*;
+use messages::onion::*;
+use messages::onion::Onion;
+use messages::onion::Onion::*;
+use messages::auth::*;
+use messages::auth::Auth::*;
+use messages::rps::*;
+use messages::rps::Rps::*;
+use messages::p2p;
+use messages::p2p::{P2PMessage, Datagram, Relay, Capabilities, Agreement, Answer, PROTOCOL_VERSIONS};
+use config;
+use logger;
+use logger::{Traffic, Direction};
+
+mod hop;
+mod keepalive;
+mod limit;
+mod replay;
+mod routing;
+mod stream;
+mod window;
+#[cfg(test)]
+mod tests;
+
+use self::keepalive::Keepalive;
+use self::replay::{SequenceNumbers, ReplayWindow};
+use self::routing::Routes;
+use self::limit::Relays;
+use self::stream::{Streams, TUNNEL_STREAM};
+use self::window::{SendWindow, ReceiveWindow, Overflow};
+
+pub use self::window::MIN_SEND_WINDOW;
+
+// How often a single hop is attempted before the tunnel build is given up
+const MAX_HOP_ATTEMPTS: u8 = 3;
+// How long the core waits for streams before retrying the backlogs of busy state machines
+const BACKLOG_RETRY_INTERVAL: u64 = 10;
+
+// The assumption here being once this counter wraps around previous tunnels/requests should be already dead
+static NEXT_TUNNEL_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+static NEXT_REQUEST_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct Communication {
+    tunnel_id: u32,
+    receiver: mpsc::Receiver<StreamType>,
+    sender: mpsc::Sender<StreamType>,
+    transport: Transport,
+    routes: Routes,
+    relays: Relays,
+    // Streams which arrived while waiting for something else
+    deferred: RefCell<VecDeque<StreamType>>,
+    capacity: usize,
+    timeout: Duration
+}
+impl Communication {
+    /** Everything goes to the client owning the tunnel - tunnels nobody owns yet talk to the designated one **/
+    fn send(&self, message: Message) {
+        self.routes.expect_reply(self.tunnel_id, &message);
+        let client = self.routes.owner(self.tunnel_id).or_else(|| {
+            let designated = self.routes.designated();
+            // Incomming tunnels belong to the client they are announced to
+            if let (&Onion(TunnelIncomming(_)), Some(client)) = (&message, designated) {
+                self.routes.own(self.tunnel_id, client);
+            }
+            designated
+        });
+        logger::traffic(Traffic {
+            tunnel_id: Some(self.tunnel_id),
+            peer: None,
+            message_type: message.name(),
+            direction: Direction::Outgoing
+        });
+        self.sender.send(StreamType::API(client, message));
+    }
+
+    fn log_incomming(&self, stream: &StreamType) {
+        logger::traffic(Traffic {
+            tunnel_id: Some(self.tunnel_id),
+            peer: match *stream {
+                StreamType::P2P(source, _) => Some(source),
+                _ => None
+            },
+            message_type: stream.name(),
+            direction: Direction::Incomming
+        });
+    }
+
+    fn receive_stream(&self, waiting_for: &'static str) -> Result<StreamType> {
+        let stream = self.receiver.recv_timeout(self.timeout)
+            .chain_err(|| ErrorKind::Timeout(waiting_for.to_string()))?;
+        self.log_incomming(&stream);
+        Ok(stream)
+    }
+
+    /**
+        Tunnel data from either direction is kept for later while waiting for a reply - up to the channel's capacity
+        Beyond it data from other nodes is dropped and the API client's rejected. Control messages are always kept
+    **/
+    fn defer(&self, stream: StreamType) {
+        let mut deferred = self.deferred.borrow_mut();
+        if deferred.len() < self.capacity {
+            return deferred.push_back(stream);
+        }
+
+        match stream {
+            StreamType::P2P(source, ref datagram) if datagram.message.message_type == p2p::P2P::Data =>
+                note!(format!("tunnel {} is congested - dropping data from {}", self.tunnel_id, source)),
+            StreamType::API(_, Onion(TunnelData(_))) =>
+                stream::reject(self.tunnel_id, MessageId::OnionTunnelData, "tunnel is congested", self),
+            StreamType::API(_, Onion(StreamData(_))) =>
+                stream::reject(self.tunnel_id, MessageId::OnionStreamData, "tunnel is congested", self),
+            stream => deferred.push_back(stream)
+        }
+    }
+
+    /**
+        Waits for a module's reply to a previous request - Auth rejections are turned into errors right away
+        Onion API messages and P2P messages are deferred until the state machine waits for them
+    **/
+    fn receive(&self, waiting_for: &'static str) -> Result<Message> {
+        loop {
+            match self.receive_stream(waiting_for)? {
+                StreamType::API(_, Auth(SessionError(message))) => bail!(ErrorKind::AuthFailure(message.request_id)),
+                stream @ StreamType::API(_, Onion(_)) | stream @ StreamType::P2P(..) => self.defer(stream),
+                StreamType::API(_, message) => return Ok(message),
+                stream => bail!(ErrorKind::ProtocolBreach(waiting_for.to_string(), stream.name().to_string()))
+            }
+        }
+    }
+
+    /** Waits for a hop to reply to a previous control message - deferred P2P messages come first **/
+    fn receive_from_hop(&self, waiting_for: &'static str) -> Result<(SocketAddr, Datagram)> {
+        let position = self.deferred.borrow().iter().position(|stream| match *stream {
+            StreamType::P2P(..) => true,
+            _ => false
+        });
+        if let Some(StreamType::P2P(source, datagram)) = position.and_then(|position| {
+            self.deferred.borrow_mut().remove(position)
+        }) {
+            return Ok((source, datagram));
+        }
+
+        loop {
+            match self.receive_stream(waiting_for)? {
+                StreamType::P2P(source, datagram) => return Ok((source, datagram)),
+                stream @ StreamType::API(_, Onion(_)) => self.defer(stream),
+                stream => bail!(ErrorKind::ProtocolBreach(waiting_for.to_string(), stream.name().to_string()))
+            }
+        }
+    }
+
+    /** Waits for the next message until the deadline passed - deferred ones come first **/
+    fn wait_until(&self, deadline: Instant) -> Result<Option<StreamType>> {
+        if let Some(stream) = self.deferred.borrow_mut().pop_front() {
+            return Ok(Some(stream));
+        }
+
+        let now = Instant::now();
+        let timeout = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+        match self.receiver.recv_timeout(timeout) {
+            Ok(stream) => {
+                self.log_incomming(&stream);
+                Ok(Some(stream))
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!("core disconnected")
+        }
+    }
+}
+
+fn breach(expected: &str, received: &Message) -> ErrorKind {
+    ErrorKind::ProtocolBreach(expected.to_string(), received.name().to_string())
+}
+
+fn p2p_breach(expected: &str, received: &P2PMessage) -> ErrorKind {
+    ErrorKind::ProtocolBreach(expected.to_string(), received.message_type.name().to_string())
+}
+
+/** A hop of a tunnel we built - cells it wraps on their way back are checked against its own window **/
+struct AuthSession {
+    session_id: u16,
+    digest_key: DigestKey,
+    rps_peer: RpsPeer,
+    agreement: Agreement,
+    backward: ReplayWindow,
+    // Cells which failed the hop's digest - anyone can send datagrams which look like the first hop's
+    forged: u64
+}
+
+/** A tunnel we built - cells to all hops go through the link to the first one **/
+struct Tunnel {
+    link: Option<Connection>,
+    hops: Vec<AuthSession>,
+    forward: SequenceNumbers,
+    streams: Streams,
+    window: SendWindow,
+    // Cells received from the destination
+    received: ReceiveWindow,
+    keepalive: Keepalive
+}
+impl Tunnel {
+    fn new(conf: &config::Config) -> Tunnel {
+        Tunnel {
+            link: None,
+            hops: vec![],
+            forward: SequenceNumbers::new(),
+            streams: Streams::new(),
+            window: SendWindow::new(conf.send_window),
+            received: ReceiveWindow::new(),
+            keepalive: Keepalive::from_config(conf)
+        }
+    }
+}
+
+pub enum StreamType {
+    /** A message from or for an API client - messages from the core itself come from no client **/
+    API(Option<Token>, Message),
+    /** A message from another node and the address it was sent from **/
+    P2P(SocketAddr, Datagram),
+    Signal(Signal),
+    Listener(ListenerStatus),
+    Client(ClientStatus)
+}
+impl StreamType {
+    /** Human readable type of what was streamed used for logging **/
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StreamType::API(_, ref message) => message.name(),
+            StreamType::P2P(_, ref datagram) => datagram.message.message_type.name(),
+            StreamType::Signal(_) => "Signal",
+            StreamType::Listener(_) => "ListenerStatus",
+            StreamType::Client(_) => "ClientStatus"
+        }
+    }
+}
+
+pub enum Signal {
+    Reload,
+    Shutdown
+}
+
+/** Reported by brunch's listener supervisors **/
+pub enum ListenerStatus {
+    Running(&'static str),
+    Failed(&'static str, String),
+    Abandoned(&'static str, String)
+}
+
+/** Reported by brunch's API channel as clients come and go **/
+pub enum ClientStatus {
+    Connected(Token),
+    Disconnected(Token)
+}
+
+/**
+    The core never waits for a state machine - streams which must not get lost but don't fit into its channel
+    wait in a backlog of the channel's size instead
+**/
+struct StateMachine {
+    sender: mpsc::SyncSender<StreamType>,
+    handle: JoinHandle<()>,
+    finished: Arc<AtomicBool>,
+    backlog: VecDeque<StreamType>,
+    capacity: usize
+}
+impl StateMachine {
+    fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    fn has_backlog(&self) -> bool {
+        !self.backlog.is_empty()
+    }
+
+    /** Hands the stream over behind the backlogged ones - returns it if the channel is full **/
+    fn try_send(&mut self, stream: StreamType) -> Option<StreamType> {
+        self.flush();
+        if self.has_backlog() {
+            return Some(stream);
+        }
+        match self.sender.try_send(stream) {
+            Err(mpsc::TrySendError::Full(stream)) => Some(stream),
+            // The state machine might have finished in the meantime
+            _ => None
+        }
+    }
+
+    /** Keeps the stream for later - a state machine which fell behind by a whole backlog loses it **/
+    fn queue(&mut self, tunnel_id: u32, stream: StreamType) {
+        if self.backlog.len() >= self.capacity {
+            return note!(format!("tunnel {} is not keeping up - dropping {}", tunnel_id, stream.name()));
+        }
+        self.backlog.push_back(stream);
+    }
+
+    fn flush(&mut self) {
+        while let Some(stream) = self.backlog.pop_front() {
+            match self.sender.try_send(stream) {
+                Ok(()) => (),
+                Err(mpsc::TrySendError::Full(stream)) => return self.backlog.push_front(stream),
+                Err(mpsc::TrySendError::Disconnected(_)) => return self.backlog.clear()
+            }
+        }
+    }
+}
+
+/** Marks a state machine as finished once dropped - even if its thread panics **/
+struct FinishedGuard(Arc<AtomicBool>);
+impl Drop for FinishedGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+fn request_peer(comm: &Communication) -> Result<RpsPeer> {
+    comm.send(Rps(Query(RpsQuery {})));
+    match comm.receive("RpsPeer")? {
+        Rps(Peer(rps_peer)) => Ok(rps_peer),
+        message => bail!(breach("RpsPeer", &message))
+    }
+}
+
+/** Has Auth encrypt the payload with the session - cleartext payloads aren't encrypted by any session yet **/
+fn auth_encrypt(session_id: u16, cleartext: bool, payload: Bytes, comm: &Communication) -> Result<Bytes> {
+    comm.send(Auth(CipherEncrypt(AuthCipherEncrypt(AuthCipherCrypt {
+        session_id: session_id,
+        request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst) as u32,
+        cleartext: cleartext,
+        payload: payload
+    }))));
+
+    match comm.receive("AuthCipherEncryptResp")? {
+        Auth(CipherEncryptResp(AuthCipherEncryptResp(message))) => Ok(message.payload),
+        message => bail!(breach("AuthCipherEncryptResp", &message))
+    }
+}
+
+/** Has Auth remove the session's encryption from the payload **/
+fn auth_decrypt(session_id: u16, payload: Bytes, comm: &Communication) -> Result<Bytes> {
+    comm.send(Auth(CipherDecrypt(AuthCipherDecrypt(AuthCipherCrypt {
+        session_id: session_id,
+        request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst) as u32,
+        cleartext: false,
+        payload: payload
+    }))));
+
+    match comm.receive("AuthCipherDecryptResp")? {
+        Auth(CipherDecryptResp(AuthCipherDecryptResp(message))) => Ok(message.payload),
+        message => bail!(breach("AuthCipherDecryptResp", &message))
+    }
+}
+
+/** Adds a single layer - only recognised layers are encrypted from cleartext **/
+fn encrypt_layer(session_id: u16, digest_key: &DigestKey, cell: Cell, comm: &Communication) -> Result<Bytes> {
+    let cleartext = cell.recognised;
+    let mut payload = BytesMut::with_capacity(12 + cell.payload.len());
+    cell.encode(digest_key, &mut payload)?;
+    auth_encrypt(session_id, cleartext, payload.freeze(), comm)
+}
+
+/** Removes a single layer - layers which weren't wrapped with this session fail their digest **/
+fn decrypt_layer(session_id: u16, digest_key: &DigestKey, data: Bytes, comm: &Communication) -> Result<Cell> {
+    Cell::decode(auth_decrypt(session_id, data, comm)?, digest_key)
+}
+
+/**
+    Wraps the data in one layer per hop - the last hop's layer is the innermost and only recognised one
+    Every layer is numbered and digested inside its encryption so no hop has to trust the ones before it
+**/
+fn encrypt_for_all_peers(peers: &Vec<AuthSession>, sequence: u32, stream_id: u16, data: Bytes,
+    comm: &Communication) -> Result<Bytes> {
+    let mut data = data;
+
+    for (layer, peer) in peers.iter().rev().enumerate() {
+        data = encrypt_layer(peer.session_id, &peer.digest_key, Cell {
+            recognised: layer == 0,
+            stream_id: if layer == 0 { stream_id } else { TUNNEL_STREAM },
+            sequence: sequence,
+            payload: data
+        }, comm)?;
+    }
+
+    Ok(data)
+}
+
+/**
+    Peels a cell which came back through the tunnel - the first hop's layer is the outermost
+    Every hop wraps what it sends back in a recognised layer, so the first recognised one tells who sent it
+    and on which stream. Replayed cells and ones failing a hop's digest are dropped - cells which passed every
+    digest without any hop having wrapped them have been tampered with
+**/
+fn decrypt_from_all_peers(peers: &mut Vec<AuthSession>, data: Bytes, comm: &Communication)
+    -> Result<Option<(usize, Cell)>> {
+    let mut data = data;
+
+    for (index, peer) in peers.iter_mut().enumerate() {
+        let cell = match decrypt_layer(peer.session_id, &peer.digest_key, data, comm) {
+            Ok(cell) => cell,
+            Err(::errors::Error(ErrorKind::Tampered(reason), _)) => {
+                note!(format!("dropping cell which failed the digest of hop {} - {}", index + 1, reason));
+                peer.forged += 1;
+                return Ok(None);
+            },
+            Err(e) => return Err(e)
+        };
+        if !peer.backward.accept(cell.sequence) {
+            note!(format!("dropping replayed cell {} wrapped by hop {}", cell.sequence, index + 1));
+            return Ok(None);
+        }
+        if cell.recognised {
+            return Ok(Some((index, cell)));
+        }
+        data = cell.payload;
+    }
+
+    bail!(ErrorKind::Tampered("none of the layers was recognised".to_string()))
+}
+
+/**
+    Link to a hop - replies from the hop reach the state machine through the core
+    Everything is addressed to the hop's end of the tunnel, which it tells us when answering our knock
+    The tunnel runs over the transport's link to the hop for as long as the connection is around
+**/
+struct Connection {
+    tunnel_id: u32,
+    hop_tunnel_id: u32,
+    peer: SocketAddr,
+    // Our place on the transport's link to the hop
+    attachment: u64,
+    transport: Transport
+}
+impl Connection {
+    /** Only the hostkey of a hop we connect to is known - hops knocking on us stay anonymous **/
+    fn open(tunnel_id: u32, hop_tunnel_id: u32, peer: SocketAddr, hostkey: Option<&Bytes>, transport: &Transport)
+        -> Result<Connection> {
+        let attachment = transport.attach(peer, hostkey)?;
+        Ok(Connection {
+            tunnel_id: tunnel_id,
+            hop_tunnel_id: hop_tunnel_id,
+            peer: peer,
+            attachment: attachment,
+            transport: transport.clone()
+        })
+    }
+
+    fn log(&self, message: &P2PMessage, direction: Direction) {
+        logger::traffic(Traffic {
+            tunnel_id: Some(self.tunnel_id),
+            peer: Some(self.peer),
+            message_type: message.message_type.name(),
+            direction: direction
+        });
+    }
+
+    fn send(&mut self, message: P2PMessage) -> Result<()> {
+        self.log(&message, Direction::Outgoing);
+        let datagram = Datagram {
+            receiver_tunnel_id: self.hop_tunnel_id,
+            sender_tunnel_id: self.tunnel_id,
+            message: message
+        };
+
+        self.transport.send(self.peer, datagram)
+    }
+
+    /**
+        Whether the datagram was sent by the hop's end of the tunnel
+        The hop's end is only known once it answered the knock, so the answer itself is taken from its address
+    **/
+    fn is_from(&mut self, source: SocketAddr, datagram: &Datagram) -> bool {
+        if source != self.peer {
+            return false;
+        }
+        if self.hop_tunnel_id == 0 && datagram.message.message_type == p2p::P2P::WhosThere {
+            self.hop_tunnel_id = datagram.sender_tunnel_id;
+        }
+        datagram.sender_tunnel_id == self.hop_tunnel_id
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.transport.detach(self.peer, self.attachment);
+    }
+}
+
+/**
+    Sends a control message to the hop being added and waits for its reply
+    The first hop is talked to directly - all later ones are asked for by the last hop of the tunnel built so far,
+    which relays the message and wraps the reply back to us
+**/
+fn exchange(tunnel: &mut Tunnel, peer: &RpsPeer, message: P2PMessage, waiting_for: &'static str,
+    comm: &Communication) -> Result<P2PMessage> {
+    if tunnel.hops.is_empty() {
+        let link = tunnel.link.as_mut().ok_or("tunnel has no link to its first hop")?;
+        link.send(message)?;
+
+        loop {
+            let (source, datagram) = comm.receive_from_hop(waiting_for)?;
+            if link.is_from(source, &datagram) {
+                return Ok(datagram.message);
+            }
+            note!(format!("{} from {} is not part of the tunnel - discarding", datagram.message.message_type.name(),
+                source));
+        }
+    }
+
+    send_cell(tunnel, TUNNEL_STREAM, P2PMessage::forward(&Relay {
+        ip_addr: peer.ip_addr,
+        port: peer.port,
+        message: message
+    })?, comm)?;
+
+    loop {
+        let (source, datagram) = comm.receive_from_hop(waiting_for)?;
+        let from_link = tunnel.link.as_mut().map_or(false, |link| link.is_from(source, &datagram));
+        if !from_link || datagram.message.message_type != p2p::P2P::Data {
+            note!(format!("{} from {} is not part of the tunnel - discarding", datagram.message.message_type.name(),
+                source));
+            continue;
+        }
+
+        match decrypt_from_all_peers(&mut tunnel.hops, datagram.message.payload()?, comm)? {
+            Some((index, cell)) if index + 1 == tunnel.hops.len() => return P2PMessage::decode(cell.payload),
+            Some((index, _)) => note!(format!("cell from hop {} while extending the tunnel - discarding", index + 1)),
+            None => ()
+        }
+    }
+}
+
+/** Knocks on the hop and runs the Auth handshake through the tunnel built so far **/
+fn connect_to_peer(peer: RpsPeer, tunnel: &mut Tunnel, conf: &config::Config, comm: &Communication)
+    -> Result<AuthSession> {
+    comm.send(Auth(SessionStart(AuthSessionStart {
+        request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst) as u32,
+        hostkey: peer.hostkey.clone()
+    })));
+
+    let hs1 = match comm.receive("AuthSessionHS1")? {
+        Auth(SessionHS1(message)) => message,
+        message => bail!(breach("AuthSessionHS1", &message))
+    };
+
+    let agreement = || -> Result<(Agreement, DigestKey)> {
+        // The first hop is the only one talked to directly
+        if tunnel.hops.is_empty() {
+            // A failed attempt might have used the same link
+            tunnel.link = None;
+            tunnel.link = Some(Connection::open(comm.tunnel_id, 0, SocketAddr::new(peer.ip_addr, peer.port),
+                Some(&peer.hostkey), &comm.transport)?);
+        }
+
+        let agreement = knock(tunnel, &peer, conf, comm)?;
+
+        let hs2 = exchange(tunnel, &peer, P2PMessage::carrying(p2p::P2P::Handshake, &hs1.payload),
+            "P2PHandshake", comm)?;
+        if hs2.message_type != p2p::P2P::Handshake {
+            bail!(p2p_breach("P2PHandshake", &hs2));
+        }
+
+        comm.send(Auth(SessionIncommingHS2(AuthSessionIncommingHS2(AuthSessionHS {
+            session_id: hs1.session_id,
+            request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst) as u32,
+            payload: hs2.payload()?
+        }))));
+
+        // The session is up - the hop confirms once it learnt the key its layers are digested with
+        let digest_key = DigestKey::random();
+        let mut key = BytesMut::with_capacity(16);
+        digest_key.encode(&mut key)?;
+        let confirmation = exchange(tunnel, &peer, P2PMessage::carrying(p2p::P2P::Handshake,
+            &auth_encrypt(hs1.session_id, true, key.freeze(), comm)?), "P2PHandshake", comm)?;
+        if confirmation.message_type != p2p::P2P::Handshake {
+            bail!(p2p_breach("P2PHandshake", &confirmation));
+        }
+        Ok((agreement, digest_key))
+    }();
+
+    match agreement {
+        Ok((agreement, digest_key)) => Ok(AuthSession {
+            session_id: hs1.session_id,
+            digest_key: digest_key,
+            rps_peer: peer,
+            agreement: agreement,
+            backward: ReplayWindow::new(),
+            forged: 0
+        }),
+        Err(e) => {
+            comm.send(Auth(SessionClose(AuthSessionClose {
+                session_id: hs1.session_id
+            })));
+            Err(e)
+        }
+    }
+}
+
+/** What this node offers when knocking or being knocked on **/
+fn capabilities(conf: &config::Config) -> Capabilities {
+    Capabilities {
+        versions: PROTOCOL_VERSIONS.to_vec(),
+        cell_size: conf.cell_size,
+        cover_traffic: conf.cover_traffic,
+        padding: conf.padding.clone()
+    }
+}
+
+/** Negotiates protocol version and features with the hop being added **/
+fn knock(tunnel: &mut Tunnel, peer: &RpsPeer, conf: &config::Config, comm: &Communication) -> Result<Agreement> {
+    let capabilities = capabilities(conf);
+    let reply = exchange(tunnel, peer, P2PMessage::knock(&capabilities)?, "P2PWhosThere", comm)?;
+    if reply.message_type != p2p::P2P::WhosThere {
+        bail!(p2p_breach("P2PWhosThere", &reply));
+    }
+
+    match reply.answer()? {
+        Answer::Accepted(ref agreement) if capabilities.permits(agreement) => {
+            note!(format!("hop speaks protocol version {} with {} byte cells", agreement.version,
+                agreement.cell_size));
+            Ok(agreement.clone())
+        },
+        Answer::Accepted(agreement) => bail!(ErrorKind::ProtocolBreach(
+            "an agreement within the offered capabilities".to_string(), format!("{:?}", agreement))),
+        Answer::Rejected(reason) => bail!(ErrorKind::Incompatible(reason))
+    }
+}
+
+/** Cells travel as a single datagram to the first hop, which peels off its layer **/
+fn send_cell(tunnel: &mut Tunnel, stream_id: u16, message: P2PMessage, comm: &Communication) -> Result<()> {
+    let mut cell = BytesMut::with_capacity(64);
+    message.encode(&mut cell)?;
+
+    let sequence = tunnel.forward.next()?;
+    let payload = encrypt_for_all_peers(&tunnel.hops, sequence, stream_id, cell.freeze(), comm)?;
+
+    tunnel.keepalive.sent();
+    match tunnel.link {
+        Some(ref mut link) => link.send(P2PMessage::carrying(p2p::P2P::Data, &payload)),
+        None => bail!("tunnel has no hops to send data over")
+    }
+}
+
+/** Sends queued tunnel data as far as the send window allows **/
+fn flush(tunnel: &mut Tunnel, comm: &Communication) -> Result<()> {
+    while let Some((stream_id, message)) = tunnel.window.next(tunnel.forward.used()) {
+        send_cell(tunnel, stream_id, message, comm)?;
+    }
+    Ok(())
+}
+
+/**
+    Tunnel data and stream requests are meant for the destination - the tunnel's last hop
+    Data has to fit into the send window, stream requests are sent right away
+**/
+fn send_over_data(tunnel_id: u32, tunnel: &mut Tunnel, request: Onion, comm: &Communication) -> Result<()> {
+    let (stream_id, message) = match tunnel.streams.outgoing(tunnel_id, request, comm)? {
+        Some(cell) => cell,
+        None => return Ok(())
+    };
+    if message.message_type != p2p::P2P::Data {
+        return send_cell(tunnel, stream_id, message, comm);
+    }
+
+    let overflow = tunnel.streams.overflow(stream_id);
+    if !tunnel.window.queue(stream_id, message, overflow) {
+        let request_type = if stream_id == TUNNEL_STREAM {
+            MessageId::OnionTunnelData
+        } else {
+            MessageId::OnionStreamData
+        };
+        stream::reject(tunnel_id, request_type, "tunnel is congested", comm);
+    }
+    flush(tunnel, comm)
+}
+
+/** Peels cells coming back from the destination - credit reopens the send window, all else goes to the API **/
+fn receive_over_data(tunnel_id: u32, tunnel: &mut Tunnel, datagram: Datagram, comm: &Communication) -> Result<()> {
+    let cell = match decrypt_from_all_peers(&mut tunnel.hops, datagram.message.payload()?, comm)? {
+        Some((index, cell)) if index + 1 == tunnel.hops.len() => cell,
+        Some((index, _)) => {
+            note!(format!("cell from hop {} is not tunnel data - discarding", index + 1));
+            return Ok(());
+        },
+        None => return Ok(())
+    };
+    tunnel.keepalive.heard();
+
+    let message = P2PMessage::decode(cell.payload)?;
+    // Every numbered cell takes up room in the destination's send window - credit included
+    if let Some(sequence) = tunnel.received.receive(cell.sequence) {
+        send_cell(tunnel, TUNNEL_STREAM, P2PMessage::credit(sequence)?, comm)?;
+    }
+    if message.message_type == p2p::P2P::Credit {
+        tunnel.window.credit(message.credited()?);
+        return flush(tunnel, comm);
+    }
+    // Answers to our probes have done their job by arriving
+    if message.message_type == p2p::P2P::Keepalive {
+        return Ok(());
+    }
+    tunnel.streams.incomming(tunnel_id, cell.stream_id, message, comm)
+}
+
+/** Tells the hops to forget about the tunnel and closes all Auth sessions belonging to it **/
+fn destroy_tunnel(tunnel: &mut Tunnel, comm: &Communication) -> Result<()> {
+    if let Some(ref mut link) = tunnel.link {
+        link.send(P2PMessage::new(p2p::P2P::Destroy))
+            .chain_err(|| "couldn't notify hops about tunnel destruction")?;
+    }
+
+    if tunnel.window.dropped() > 0 {
+        note!(format!("dropped {} voice cell(s) while the tunnel was congested", tunnel.window.dropped()));
+    }
+    for (index, peer) in tunnel.hops.drain(..).enumerate() {
+        if peer.backward.dropped() > 0 {
+            note!(format!("dropped {} replayed cell(s) wrapped by hop {}", peer.backward.dropped(), index + 1));
+        }
+        if peer.forged > 0 {
+            note!(format!("dropped {} cell(s) which failed the digest of hop {}", peer.forged, index + 1));
+        }
+        comm.send(Auth(SessionClose(AuthSessionClose {
+            session_id: peer.session_id
+        })));
+    }
+
+    Ok(())
+}
+
+/** Finds a usable peer for the next hop - retryable failures lead to another peer being tried **/
+fn add_hop(tunnel: &mut Tunnel, conf: &config::Config, comm: &Communication) -> Result<AuthSession> {
+    let mut attempt = 1;
+
+    loop {
+        let result = request_peer(comm).and_then(|peer| {
+            if tunnel.hops.iter().any(|hop| hop.rps_peer.ip_addr == peer.ip_addr && hop.rps_peer.port == peer.port) {
+                bail!(ErrorKind::RpsFailure("peer is already part of the tunnel".to_string()));
+            }
+            connect_to_peer(peer, tunnel, conf, comm)
+        });
+
+        match result {
+            Err(ref e) if e.is_retryable() && attempt < MAX_HOP_ATTEMPTS => {
+                note!(format!("hop {} attempt {} failed ({}) - trying another peer", tunnel.hops.len() + 1, attempt,
+                    e));
+                attempt += 1;
+            },
+            result => return result
+        }
+    }
+}
+
+/** Adds the configured amount of hops and the destination as the tunnel's last one **/
+fn build_tunnel(tunnel: &mut Tunnel, message: &OnionTunnelBuild, conf: &config::Config, comm: &Communication)
+    -> Result<()> {
+    let started = Instant::now();
+    for _ in 0..conf.min_hop_count {
+        if started.elapsed() > conf.build_timeout {
+            bail!(ErrorKind::Timeout("tunnel to be built".to_string()));
+        }
+
+        let auth_session = add_hop(tunnel, conf, comm)?;
+        tunnel.hops.push(auth_session);
+    }
+
+    // The destination is the tunnel's last hop
+    let destination = connect_to_peer(RpsPeer {
+        port: message.onion_tunnel,
+        ip_addr: message.ip_addr,
+        hostkey: message.hostkey.clone()
+    }, tunnel, conf, comm)?;
+    tunnel.hops.push(destination);
+
+    // Building might have taken longer than the far end may stay silent
+    tunnel.keepalive = Keepalive::from_config(conf);
+    Ok(())
+}
+
+/**
+    Replaces a tunnel whose far end went silent with a new one to the same destination
+    The API client keeps using the same tunnel id - its streams are opened again at the destination
+**/
+fn rebuild_tunnel(tunnel: &mut Tunnel, message: &OnionTunnelBuild, conf: &config::Config, comm: &Communication)
+    -> Result<()> {
+    trace_labeled_error!( "dead tunnel could not be destroyed cleanly", {
+        destroy_tunnel(tunnel, comm)?;
+    });
+
+    let opened = tunnel.streams.opened();
+    *tunnel = Tunnel::new(conf);
+    build_tunnel(tunnel, message, conf, comm)?;
+
+    for (stream_id, overflow) in opened {
+        tunnel.streams.open(stream_id, overflow)?;
+        send_cell(tunnel, stream_id, P2PMessage::open_stream(overflow == Overflow::DropOldest)?, comm)?;
+    }
+    Ok(())
+}
+
+fn start_dialogue(tunnel_id: u32, message: &OnionTunnelBuild, conf: &config::Config, comm: &Communication) {
+    let mut tunnel = Tunnel::new(conf);
+    let mut in_flight = MessageId::OnionTunnelBuild;
+
+    let result = || -> Result<()> {
+        build_tunnel(&mut tunnel, message, conf, comm)?;
+
+        comm.send(Onion(TunnelReady(OnionTunnelReady(OnionTunnelPayload {
+            tunnel_id: tunnel_id,
+            payload: message.hostkey.clone()
+        }))));
+
+        loop {
+            match comm.wait_until(tunnel.keepalive.deadline())? {
+                None => (),
+                Some(StreamType::API(_, Onion(TunnelDestroy(_)))) => {
+                    in_flight = MessageId::OnionTunnelDestroy;
+                    break;
+                },
+                Some(StreamType::API(_, Onion(request))) => {
+                    in_flight = MessageId::OnionTunnelData;
+                    send_over_data(tunnel_id, &mut tunnel, request, comm)?;
+                },
+                Some(StreamType::P2P(source, datagram)) => {
+                    let from_link = tunnel.link.as_mut().map_or(false, |link| link.is_from(source, &datagram));
+                    match datagram.message.message_type {
+                        _ if !from_link => note!(format!("{} from {} is not part of the tunnel - discarding",
+                            datagram.message.message_type.name(), source)),
+                        p2p::P2P::Data => {
+                            in_flight = MessageId::OnionTunnelData;
+                            receive_over_data(tunnel_id, &mut tunnel, datagram, comm)?;
+                        },
+                        p2p::P2P::Destroy => bail!("tunnel was destroyed by its first hop"),
+                        _ => bail!(p2p_breach("P2PData or P2PDestroy", &datagram.message))
+                    }
+                },
+                Some(stream) => bail!(ErrorKind::ProtocolBreach("OnionTunnelData or OnionTunnelDestroy".to_string(),
+                    stream.name().to_string()))
+            }
+
+            if tunnel.keepalive.is_dead() {
+                if !conf.rebuild_dead_tunnels {
+                    bail!(ErrorKind::Timeout("keepalive from the destination".to_string()));
+                }
+                note!(format!("tunnel {} stopped responding - rebuilding it", tunnel_id));
+                in_flight = MessageId::OnionTunnelBuild;
+                rebuild_tunnel(&mut tunnel, message, conf, comm)?;
+            } else if tunnel.keepalive.is_due() {
+                send_cell(&mut tunnel, TUNNEL_STREAM, P2PMessage::new(p2p::P2P::Keepalive), comm)?;
+                tunnel.keepalive.probed();
+            }
+        }
+
+        Ok(())
+    }();
+
+    if let Err(ref e) = result {
+        comm.send(Onion(messages::onion::Onion::Error(OnionError {
+            tunnel_id: tunnel_id,
+            request_type: e.request_type(in_flight) as u16
+        })));
+    }
+
+    trace_labeled_error!( "dialogue encountered a problem", {
+        result?;
+    });
+
+    trace_labeled_error!( "tunnel could not be destroyed cleanly", {
+        destroy_tunnel(&mut tunnel, comm)?;
+    });
+}
+
+fn spinup_state_machine(tunnel_id: u32, stream: StreamType, conf: config::Config, ty: mpsc::Sender<StreamType>,
+    transport: Transport, routes: Routes, relays: Relays) -> StateMachine
+{
+    let capacity = conf.channel_capacity;
+    let (tx, rx) = mpsc::sync_channel(capacity);
+    let finished = Arc::new(AtomicBool::new(false));
+
+    let handle = {
+        let finished = FinishedGuard(finished.clone());
+
+        thread::spawn(move || {
+            let _finished = finished;
+            let stream = &stream;
+            let comm = &Communication {
+                tunnel_id: tunnel_id,
+                receiver: rx,
+                sender: ty,
+                transport: transport,
+                routes: routes,
+                relays: relays,
+                deferred: RefCell::new(VecDeque::new()),
+                capacity: conf.channel_capacity,
+                timeout: conf.reply_timeout
+            };
+
+            trace_labeled_error!("failed to create state machine", {
+                match *stream {
+                    StreamType::API(_, Onion(TunnelBuild(ref message))) =>
+                        start_dialogue(tunnel_id, message, &conf, &comm),
+                    StreamType::API(_, Onion(Cover(_))) if !conf.cover_traffic =>
+                        note!("cover traffic is disabled - discarding"),
+                    StreamType::P2P(source, ref datagram) if datagram.message.message_type == p2p::P2P::Knock =>
+                        hop::answer_dialogue(source, datagram, &conf, &comm),
+
+                    _ => note!("message {} not part of protocol - discarding")
+                };
+            });
+        })
+    };
+
+    StateMachine {
+        sender: tx,
+        handle: handle,
+        finished: finished,
+        backlog: VecDeque::new(),
+        capacity: capacity
+    }
+}
+
+/** Re-reads the config file - running state machines keep the configuration they were started with **/
+fn reload_config(conf: &config::Config) -> Result<config::Config> {
+    let reload = conf.reload()?;
+
+    logger::configure(&reload.config)?;
+
+    status!("Configuration reloaded");
+    if reload.applied.is_empty() && reload.requires_restart.is_empty() {
+        note!("nothing changed");
+    }
+    for property in reload.applied {
+        note!(format!("[{}] changed - applied", property));
+    }
+    for property in reload.requires_restart {
+        status!(format!("[{}] changed - requires a restart to take effect", property), "warn");
+    }
+
+    Ok(reload.config)
+}
+
+/** Asks the state machine to destroy its tunnel as if its API client did **/
+fn destroy(tunnel_id: u32, state_machine: &mut StateMachine) {
+    let request = StreamType::API(None, Onion(TunnelDestroy(OnionTunnelDestroy(OnionTunnelID {
+        tunnel_id: tunnel_id
+    }))));
+    if let Some(request) = state_machine.try_send(request) {
+        state_machine.queue(tunnel_id, request);
+    }
+}
+
+/** Tells only the API client which sent the request that it failed **/
+fn reject(ty: &mpsc::Sender<StreamType>, client: Option<Token>, tunnel_id: u32, request: &Message, reason: &str) {
+    note!(format!("rejecting {} for tunnel {} - {}", request.name(), tunnel_id, reason));
+    let _ = ty.send(StreamType::API(client, Onion(messages::onion::Onion::Error(OnionError {
+        tunnel_id: tunnel_id,
+        request_type: request.id() as u16
+    }))));
+}
+
+/** Destroys all tunnels and waits for their state machines until the shutdown deadline passes **/
+fn shutdown(state_machines: HashMap<u32, StateMachine>, conf: &config::Config) -> Result<()> {
+    status!("Shutting down - destroying all tunnels", "warn");
+
+    let mut state_machines = state_machines;
+    for (tunnel_id, state_machine) in &mut state_machines {
+        destroy(*tunnel_id, state_machine);
+    }
+
+    let deadline = Instant::now() + conf.shutdown_timeout;
+
+    while !state_machines.is_empty() && Instant::now() < deadline {
+        for state_machine in state_machines.values_mut() {
+            state_machine.flush();
+        }
+
+        let finished: Vec<u32> = state_machines.iter()
+            .filter(|&(_, state_machine)| state_machine.is_finished())
+            .map(|(tunnel_id, _)| *tunnel_id)
+            .collect();
+
+        for tunnel_id in finished {
+            if let Some(state_machine) = state_machines.remove(&tunnel_id) {
+                let _ = state_machine.handle.join();
+            }
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    if !state_machines.is_empty() {
+        bail!("{} state machine(s) did not finish before the shutdown deadline", state_machines.len());
+    }
+
+    note!("all tunnels destroyed");
+    Ok(())
+}
+
+/**
+    Hands a datagram to the state machine of the tunnel it is addressed to
+    Tunnel data is dropped while the state machine is busy - like any other lost datagram - control messages
+    were acknowledged already and wait in its backlog instead
+**/
+fn deliver(state_machines: &mut HashMap<u32, StateMachine>, source: SocketAddr, datagram: Datagram) {
+    let state_machine = match state_machines.get_mut(&datagram.receiver_tunnel_id) {
+        Some(state_machine) => state_machine,
+        None => return note!(format!("{} from {} for unknown tunnel {} - discarding",
+            datagram.message.message_type.name(), source, datagram.receiver_tunnel_id))
+    };
+
+    let tunnel_id = datagram.receiver_tunnel_id;
+    let is_data = datagram.message.message_type == p2p::P2P::Data;
+
+    match state_machine.try_send(StreamType::P2P(source, datagram)) {
+        Some(_) if is_data => note!(format!("tunnel {} is congested - dropping data from {}", tunnel_id, source)),
+        Some(stream) => state_machine.queue(tunnel_id, stream),
+        None => ()
+    }
+}
+
+/**
+    Hands an API message to the state machine of the tunnel it is meant for
+    Requests are rejected while the state machine is busy, module replies wait in its backlog instead
+**/
+fn route(state_machine: &mut StateMachine, tunnel_id: u32, client: Option<Token>, message: Message,
+    ty: &mpsc::Sender<StreamType>) {
+    match state_machine.try_send(StreamType::API(client, message)) {
+        Some(StreamType::API(client, ref request @ Onion(_))) => reject(ty, client, tunnel_id, request,
+            "tunnel is congested"),
+        Some(stream) => state_machine.queue(tunnel_id, stream),
+        None => ()
+    }
+}
+
+pub fn start(rx: &mpsc::Receiver<StreamType>, ty: mpsc::Sender<StreamType>, transport: Transport,
+    conf: config::Config) -> Result<()> {
+
+    let mut conf = conf;
+    let mut state_machines: HashMap<u32, StateMachine> = HashMap::new();
+    let routes = Routes::new();
+    let relays = Relays::new(&conf);
+
+    // A loop represents one app round
+    loop {
+        for state_machine in state_machines.values_mut() {
+            state_machine.flush();
+        }
+
+        // Backlogs are retried every so often rather than only once the next stream arrives
+        let stream = if state_machines.values().any(StateMachine::has_backlog) {
+            match rx.recv_timeout(Duration::from_millis(BACKLOG_RETRY_INTERVAL)) {
+                Ok(stream) => stream,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => bail!("core channel disconnected")
+            }
+        } else {
+            status!("Waiting for stream");
+            rx.recv().chain_err(|| "core channel disconnected")?
+        };
+
+        match stream {
+            StreamType::Signal(Signal::Reload) => {
+                trace_labeled_error!("failed to reload configuration", {
+                    conf = reload_config(&conf)?;
+                });
+            },
+            StreamType::Signal(Signal::Shutdown) => {
+                return shutdown(state_machines, &conf);
+            },
+            StreamType::Client(ClientStatus::Connected(client)) => routes.connected(client),
+            // Nobody is left to use the tunnels of a client which went away
+            StreamType::Client(ClientStatus::Disconnected(client)) => {
+                for tunnel_id in routes.disconnected(client) {
+                    if let Some(state_machine) = state_machines.get_mut(&tunnel_id) {
+                        destroy(tunnel_id, state_machine);
+                    }
+                }
+            },
+            StreamType::Listener(ListenerStatus::Running(listener)) => {
+                note!(format!("{} listener is up and running", listener));
+            },
+            StreamType::Listener(ListenerStatus::Failed(listener, error)) => {
+                status!(format!("{} listener failed - restarting: {}", listener, error), "warn");
+            },
+            StreamType::Listener(ListenerStatus::Abandoned(listener, error)) => {
+                status!(format!("{} listener exceeded its failure budget: {}", listener, error), "error");
+                shutdown(state_machines, &conf)?;
+                bail!("{} listener could not be kept running", listener);
+            },
+            // Tunnels other nodes are building through us are addressed by the knocker from now on
+            StreamType::P2P(source, datagram) => {
+                if datagram.message.message_type != p2p::P2P::Knock {
+                    deliver(&mut state_machines, source, datagram);
+                } else {
+                    let tunnel_id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::SeqCst) as u32;
+                    state_machines.insert(tunnel_id, spinup_state_machine(tunnel_id,
+                        StreamType::P2P(source, datagram), conf.clone(), ty.clone(), transport.clone(),
+                        routes.clone(), relays.clone()));
+                }
+            },
+            // Replies and tunnel traffic go to the state machine waiting for them
+            StreamType::API(client, message) => match routes.route(&message) {
+                // Only the sender learns that it addressed a tunnel it doesn't own
+                Some(tunnel_id) if !routes.may_address(tunnel_id, &message, client) =>
+                    reject(&ty, client, tunnel_id, &message, "the API client doesn't own the tunnel"),
+                Some(tunnel_id) => match state_machines.get_mut(&tunnel_id) {
+                    Some(state_machine) => route(state_machine, tunnel_id, client, message, &ty),
+                    None => note!(format!("{} for unknown tunnel {} - discarding", message.name(), tunnel_id))
+                },
+                // Spinup state machines for received communication - tunnels belong to the client building them
+                None => {
+                    let tunnel_id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::SeqCst) as u32;
+                    if let Some(client) = client {
+                        routes.own(tunnel_id, client);
+                    }
+                    state_machines.insert(tunnel_id, spinup_state_machine(tunnel_id,
+                        StreamType::API(client, message), conf.clone(), ty.clone(), transport.clone(),
+                        routes.clone(), relays.clone()));
+                }
+            }
+        };
+
+        state_machines.retain(|tunnel_id, state_machine| {
+            if state_machine.is_finished() {
+                routes.forget(*tunnel_id);
+            }
+            !state_machine.is_finished()
+        });
+    };
+}
@@ -0,0 +1,82 @@
+// This module is responsible for numbering tunnel cells and telling replayed ones apart
+use errors::*;
+
+// How far behind the highest sequence number seen a cell may arrive and still be accepted
+const WINDOW_SIZE: u32 = 64;
+
+/** Numbers the cells sent in one direction of a tunnel - numbers are never reused **/
+pub struct SequenceNumbers {
+    next: u64
+}
+impl SequenceNumbers {
+    pub fn new() -> SequenceNumbers {
+        SequenceNumbers { next: 0 }
+    }
+
+    /** Tunnels which used up all sequence numbers have to be rebuilt **/
+    pub fn next(&mut self) -> Result<u32> {
+        if self.next > u64::from(::std::u32::MAX) {
+            bail!("sequence numbers of the tunnel are exhausted");
+        }
+        self.next += 1;
+        Ok((self.next - 1) as u32)
+    }
+
+    /** Amount of sequence numbers handed out so far **/
+    pub fn used(&self) -> u64 {
+        self.next
+    }
+}
+
+/**
+    Sliding window over the sequence numbers received in one direction of a tunnel
+    Cells may arrive out of order within the window - cells seen before or older than the window are dropped
+**/
+pub struct ReplayWindow {
+    highest: Option<u32>,
+    // Bit n is set if `highest - n` has been seen already
+    seen: u64,
+    dropped: u64
+}
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow {
+            highest: None,
+            seen: 0,
+            dropped: 0
+        }
+    }
+
+    /** Returns whether the cell is new - replays are counted instead **/
+    pub fn accept(&mut self, sequence: u32) -> bool {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(sequence);
+                self.seen = 1;
+                return true;
+            }
+        };
+
+        if sequence > highest {
+            let advance = sequence - highest;
+            self.seen = if advance >= WINDOW_SIZE { 0 } else { self.seen << advance };
+            self.seen |= 1;
+            self.highest = Some(sequence);
+            return true;
+        }
+
+        let age = highest - sequence;
+        if age >= WINDOW_SIZE || self.seen & (1 << age) != 0 {
+            self.dropped += 1;
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+
+    /** Amount of replayed or hopelessly late cells dropped so far **/
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
@@ -0,0 +1,119 @@
+// This module is responsible for finding the state machine a message from the API is meant for
+use messages::Message;
+use messages::Message::*;
+use messages::onion::*;
+use messages::onion::Onion::*;
+use messages::auth::*;
+use messages::auth::Auth::*;
+use messages::rps::Rps::*;
+
+use mio::Token;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+struct Pending {
+    requests: HashMap<u32, u32>,
+    peer_queries: VecDeque<u32>
+}
+
+/**
+    Onion API messages on existing tunnels name their tunnel, whose id doubles as the id of its state machine
+    Module replies only carry a request id (Auth) or nothing at all (RPS) - state machines
+    note which replies they are waiting for when sending a request, the core looks them up again
+    Tunnels belong to the API client which built them or was told about them when they came in.
+    Tunnels no API client owns yet talk to the designated client - the longest connected one
+**/
+#[derive(Clone)]
+pub struct Routes {
+    pending: Arc<Mutex<Pending>>,
+    owners: Arc<Mutex<HashMap<u32, Token>>>,
+    clients: Arc<Mutex<Vec<Token>>>
+}
+impl Routes {
+    pub fn new() -> Routes {
+        Routes {
+            pending: Arc::new(Mutex::new(Pending {
+                requests: HashMap::new(),
+                peer_queries: VecDeque::new()
+            })),
+            owners: Arc::new(Mutex::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(vec![]))
+        }
+    }
+
+    pub fn own(&self, tunnel_id: u32, client: Token) {
+        self.owners.lock().unwrap().insert(tunnel_id, client);
+    }
+
+    pub fn owner(&self, tunnel_id: u32) -> Option<Token> {
+        self.owners.lock().unwrap().get(&tunnel_id).cloned()
+    }
+
+    /** Onion messages may only address tunnels owned by the client sending them - module replies come from anyone **/
+    pub fn may_address(&self, tunnel_id: u32, message: &Message, client: Option<Token>) -> bool {
+        match *message {
+            Onion(_) => client.is_some() && self.owner(tunnel_id) == client,
+            _ => true
+        }
+    }
+
+    pub fn connected(&self, client: Token) {
+        self.clients.lock().unwrap().push(client);
+    }
+
+    /** Returns the tunnels the client owned **/
+    pub fn disconnected(&self, client: Token) -> Vec<u32> {
+        self.clients.lock().unwrap().retain(|connected| *connected != client);
+        self.owners.lock().unwrap().iter()
+            .filter(|&(_, owner)| *owner == client)
+            .map(|(tunnel_id, _)| *tunnel_id)
+            .collect()
+    }
+
+    pub fn designated(&self) -> Option<Token> {
+        self.clients.lock().unwrap().first().cloned()
+    }
+
+    /** Called for every message a state machine sends to the API **/
+    pub fn expect_reply(&self, tunnel_id: u32, request: &Message) {
+        let mut pending = self.pending.lock().unwrap();
+        match *request {
+            Rps(Query(_)) => pending.peer_queries.push_back(tunnel_id),
+            Auth(SessionStart(ref request)) => { pending.requests.insert(request.request_id, tunnel_id); },
+            Auth(SessionIncommingHS1(ref request)) => { pending.requests.insert(request.request_id, tunnel_id); },
+            Auth(CipherEncrypt(AuthCipherEncrypt(ref request))) |
+                Auth(CipherDecrypt(AuthCipherDecrypt(ref request))) => {
+                pending.requests.insert(request.request_id, tunnel_id);
+            },
+            _ => ()
+        }
+    }
+
+    /** The tunnel whose state machine the message is meant for - none if it starts a new one **/
+    pub fn route(&self, message: &Message) -> Option<u32> {
+        let mut pending = self.pending.lock().unwrap();
+        match *message {
+            Onion(TunnelData(ref message)) => Some(message.tunnel_id),
+            Onion(TunnelDestroy(ref message)) => Some(message.tunnel_id),
+            Onion(StreamOpen(OnionStreamOpen(ref message))) | Onion(StreamClose(OnionStreamClose(ref message))) =>
+                Some(message.tunnel_id),
+            Onion(StreamData(ref message)) => Some(message.tunnel_id),
+            Rps(Peer(_)) => pending.peer_queries.pop_front(),
+            Auth(SessionHS1(AuthSessionHS1(ref reply))) | Auth(SessionHS2(AuthSessionHS2(ref reply))) =>
+                pending.requests.remove(&reply.request_id),
+            Auth(CipherEncryptResp(AuthCipherEncryptResp(ref reply))) |
+                Auth(CipherDecryptResp(AuthCipherDecryptResp(ref reply))) => pending.requests.remove(&reply.request_id),
+            Auth(SessionError(ref reply)) => pending.requests.remove(&reply.request_id),
+            _ => None
+        }
+    }
+
+    /** Replies to a finished state machine have nowhere to go anymore **/
+    pub fn forget(&self, tunnel_id: u32) {
+        self.owners.lock().unwrap().remove(&tunnel_id);
+        let mut pending = self.pending.lock().unwrap();
+        pending.requests.retain(|_, waiting| *waiting != tunnel_id);
+        pending.peer_queries.retain(|waiting| *waiting != tunnel_id);
+    }
+}
@@ -0,0 +1,148 @@
+// This module is responsible for the streams multiplexed over a single tunnel
+use std::collections::HashMap;
+
+use errors::*;
+use messages::MessageId;
+use messages::Message::*;
+use messages::onion::*;
+use messages::onion::Onion;
+use messages::onion::Onion::*;
+use messages::p2p::{P2P, P2PMessage};
+
+use super::Communication;
+use super::window::Overflow;
+
+// Carries `OnionTunnelData` and is open for as long as the tunnel is
+pub const TUNNEL_STREAM: u16 = 0;
+
+/** Tells the API client its request failed - the tunnel stays up **/
+pub fn reject(tunnel_id: u32, request_type: MessageId, reason: &str, comm: &Communication) {
+    note!(format!("rejecting request of the API client - {}", reason));
+    comm.send(Onion(::messages::onion::Onion::Error(OnionError {
+        tunnel_id: tunnel_id,
+        request_type: request_type as u16
+    })));
+}
+
+/**
+    Streams open on one tunnel - either end may open and close them
+    Requests the API client shouldn't have made are answered with an `OnionError` but leave the tunnel up
+    The tunnel itself carries voice
+**/
+pub struct Streams {
+    open: HashMap<u16, Overflow>
+}
+impl Streams {
+    pub fn new() -> Streams {
+        Streams { open: HashMap::new() }
+    }
+
+    pub fn is_open(&self, stream_id: u16) -> bool {
+        stream_id == TUNNEL_STREAM || self.open.contains_key(&stream_id)
+    }
+
+    /** How the stream's cells are treated while the tunnel is congested **/
+    pub fn overflow(&self, stream_id: u16) -> Overflow {
+        match self.open.get(&stream_id) {
+            Some(overflow) => *overflow,
+            None => Overflow::DropOldest
+        }
+    }
+
+    /** Streams besides the tunnel itself along with how they treat congestion **/
+    pub fn opened(&self) -> Vec<(u16, Overflow)> {
+        self.open.iter().map(|(&stream_id, &overflow)| (stream_id, overflow)).collect()
+    }
+
+    pub fn open(&mut self, stream_id: u16, overflow: Overflow) -> Result<()> {
+        if self.is_open(stream_id) {
+            bail!("stream {} is already open", stream_id);
+        }
+        self.open.insert(stream_id, overflow);
+        Ok(())
+    }
+
+    pub fn close(&mut self, stream_id: u16) -> Result<()> {
+        if stream_id == TUNNEL_STREAM {
+            bail!("stream {} is the tunnel itself - it is closed by destroying the tunnel", stream_id);
+        }
+        if self.open.remove(&stream_id).is_none() {
+            bail!("stream {} is not open", stream_id);
+        }
+        Ok(())
+    }
+
+    /** Turns a request of the API client into the message to send to the far end along with its stream **/
+    pub fn outgoing(&mut self, tunnel_id: u32, message: Onion, comm: &Communication)
+        -> Result<Option<(u16, P2PMessage)>> {
+        let (request_type, result) = match message {
+            TunnelData(message) =>
+                return Ok(Some((TUNNEL_STREAM, P2PMessage::carrying(P2P::Data, &message.payload)))),
+            StreamData(message) => (MessageId::OnionStreamData, if self.is_open(message.stream_id) {
+                Ok((message.stream_id, P2PMessage::carrying(P2P::Data, &message.payload)))
+            } else {
+                Err(format!("stream {} is not open", message.stream_id).into())
+            }),
+            StreamOpen(message) => {
+                let overflow = if message.voice { Overflow::DropOldest } else { Overflow::Reject };
+                (MessageId::OnionStreamOpen, self.open(message.stream_id, overflow)
+                    .and_then(|_| Ok((message.stream_id, P2PMessage::open_stream(message.voice)?))))
+            },
+            StreamClose(message) => (MessageId::OnionStreamClose, self.close(message.stream_id)
+                .map(|_| (message.stream_id, P2PMessage::new(P2P::StreamClose)))),
+            message => bail!(ErrorKind::ProtocolBreach("OnionTunnelData or a stream request".to_string(),
+                Onion(message).name().to_string()))
+        };
+
+        match result {
+            Ok(cell) => Ok(Some(cell)),
+            Err(e) => {
+                reject(tunnel_id, request_type, &format!("{}", e), comm);
+                Ok(None)
+            }
+        }
+    }
+
+    /** Hands a message the far end sent on one of the tunnel's streams to the API client **/
+    pub fn incomming(&mut self, tunnel_id: u32, stream_id: u16, message: P2PMessage, comm: &Communication)
+        -> Result<()> {
+        match message.message_type {
+            P2P::Data if stream_id == TUNNEL_STREAM => comm.send(Onion(TunnelData(OnionTunnelData(OnionTunnelPayload {
+                tunnel_id: tunnel_id,
+                payload: message.payload()?
+            })))),
+            P2P::Data if self.is_open(stream_id) => comm.send(Onion(StreamData(OnionStreamPayload {
+                tunnel_id: tunnel_id,
+                stream_id: stream_id,
+                payload: message.payload()?
+            }))),
+            P2P::Data => note!(format!("data for stream {} which is not open - discarding", stream_id)),
+            P2P::StreamOpen | P2P::StreamClose => {
+                let voice = if message.message_type == P2P::StreamOpen { Some(message.voice()?) } else { None };
+                let result = match voice {
+                    Some(true) => self.open(stream_id, Overflow::DropOldest),
+                    Some(false) => self.open(stream_id, Overflow::Reject),
+                    None => self.close(stream_id)
+                };
+                if let Err(e) = result {
+                    note!(format!("{} from the far end - discarding: {}", message.message_type.name(), e));
+                    return Ok(());
+                }
+
+                let stream = OnionStream {
+                    tunnel_id: tunnel_id,
+                    stream_id: stream_id,
+                    voice: voice.unwrap_or(false)
+                };
+                comm.send(Onion(if voice.is_some() {
+                    StreamOpen(OnionStreamOpen(stream))
+                } else {
+                    StreamClose(OnionStreamClose(stream))
+                }));
+            },
+            _ => bail!(ErrorKind::ProtocolBreach("P2PData, P2PStreamOpen or P2PStreamClose".to_string(),
+                message.message_type.name().to_string()))
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use bytes::Bytes;
+use mio::Token;
+
+use core::replay::{ReplayWindow, SequenceNumbers};
+use core::routing::Routes;
+use core::keepalive::Keepalive;
+use core::limit::TokenBucket;
+use core::stream::{Streams, TUNNEL_STREAM};
+use core::window::{SendWindow, ReceiveWindow, Overflow, CREDIT_BATCH, MIN_SEND_WINDOW};
+use messages::p2p::{P2P, P2PMessage};
+use messages::Message::*;
+use messages::auth::*;
+use messages::auth::Auth::*;
+use messages::onion::*;
+use messages::onion::Onion::*;
+use messages::rps::*;
+use messages::rps::Rps::*;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn sequence_numbers_count_up_from_zero() {
+    let mut sequence = SequenceNumbers::new();
+    assert_eq!(sequence.next().unwrap(), 0);
+    assert_eq!(sequence.next().unwrap(), 1);
+}
+
+#[test]
+fn replayed_cells_are_dropped() {
+    let mut window = ReplayWindow::new();
+    assert!(window.accept(5));
+    assert!(!window.accept(5));
+    assert!(window.accept(6));
+    assert!(!window.accept(5));
+    assert!(!window.accept(6));
+    assert_eq!(window.dropped(), 3);
+}
+
+#[test]
+fn reordered_cells_within_the_window_are_accepted_once() {
+    let mut window = ReplayWindow::new();
+    assert!(window.accept(10));
+    assert!(window.accept(8));
+    assert!(window.accept(9));
+    assert!(window.accept(7));
+    for sequence in 7..11 {
+        assert!(!window.accept(sequence));
+    }
+    assert_eq!(window.dropped(), 4);
+}
+
+#[test]
+fn cells_older_than_the_window_are_dropped() {
+    let mut window = ReplayWindow::new();
+    assert!(window.accept(0));
+    assert!(window.accept(100));
+    assert!(!window.accept(36));
+    assert!(window.accept(37));
+    assert!(window.accept(99));
+
+    // Jumping further ahead than the window forgets everything seen before
+    assert!(window.accept(1000));
+    assert!(!window.accept(100));
+    assert!(window.accept(999));
+    assert_eq!(window.dropped(), 2);
+}
+
+#[test]
+fn the_highest_sequence_number_can_be_used() {
+    let mut window = ReplayWindow::new();
+    assert!(window.accept(::std::u32::MAX - 1));
+    assert!(window.accept(::std::u32::MAX));
+    assert!(!window.accept(::std::u32::MAX));
+}
+
+#[test]
+fn replies_are_routed_to_the_state_machine_waiting_for_them() {
+    let routes = Routes::new();
+    routes.expect_reply(3, &Rps(Query(RpsQuery {})));
+    routes.expect_reply(7, &Rps(Query(RpsQuery {})));
+    routes.expect_reply(7, &Auth(CipherEncrypt(AuthCipherEncrypt(AuthCipherCrypt {
+        session_id: 1,
+        request_id: 42,
+        cleartext: true,
+        payload: Bytes::new()
+    }))));
+
+    let reply = Auth(CipherEncryptResp(AuthCipherEncryptResp(AuthCipherCryptResp {
+        request_id: 42,
+        cleartext: false,
+        payload: Bytes::new()
+    })));
+    assert_eq!(routes.route(&reply), Some(7));
+    assert_eq!(routes.route(&reply), None);
+
+    routes.forget(3);
+    let peer = Rps(Peer(RpsPeer { port: 1, ip_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), hostkey: Bytes::new() }));
+    assert_eq!(routes.route(&peer), Some(7));
+    assert_eq!(routes.route(&peer), None);
+
+    assert_eq!(routes.route(&Onion(TunnelDestroy(OnionTunnelDestroy(OnionTunnelID { tunnel_id: 9 })))), Some(9));
+}
+
+#[test]
+fn the_longest_connected_client_is_designated() {
+    let routes = Routes::new();
+    assert_eq!(routes.designated(), None);
+
+    routes.connected(Token(4));
+    routes.connected(Token(2));
+    routes.own(7, Token(4));
+    assert_eq!(routes.designated(), Some(Token(4)));
+    assert_eq!(routes.disconnected(Token(4)), vec![7]);
+    assert_eq!(routes.designated(), Some(Token(2)));
+}
+
+#[test]
+fn tunnels_belong_to_their_client_until_forgotten() {
+    let routes = Routes::new();
+    assert_eq!(routes.owner(3), None);
+
+    routes.own(3, Token(1));
+    assert_eq!(routes.owner(3), Some(Token(1)));
+
+    let destroy = Onion(TunnelDestroy(OnionTunnelDestroy(OnionTunnelID { tunnel_id: 3 })));
+    assert!(routes.may_address(3, &destroy, Some(Token(1))));
+    assert!(!routes.may_address(3, &destroy, Some(Token(2))));
+    assert!(!routes.may_address(4, &destroy, Some(Token(1))));
+    routes.forget(3);
+    assert_eq!(routes.owner(3), None);
+}
+
+#[test]
+fn streams_are_opened_and_closed_once() {
+    let mut streams = Streams::new();
+    assert!(streams.is_open(TUNNEL_STREAM));
+    assert!(!streams.is_open(1));
+
+    streams.open(1, Overflow::Reject).unwrap();
+    assert!(streams.is_open(1));
+    assert_eq!(streams.overflow(1), Overflow::Reject);
+    assert_eq!(streams.overflow(TUNNEL_STREAM), Overflow::DropOldest);
+    assert!(streams.open(1, Overflow::DropOldest).is_err());
+    assert!(streams.open(TUNNEL_STREAM, Overflow::Reject).is_err());
+
+    streams.close(1).unwrap();
+    assert!(!streams.is_open(1));
+    assert!(streams.close(1).is_err());
+    assert!(streams.close(TUNNEL_STREAM).is_err());
+}
+
+fn data(byte: u8) -> P2PMessage {
+    P2PMessage::carrying(P2P::Data, &[byte])
+}
+
+#[test]
+fn send_windows_hold_cells_back_until_credited() {
+    let mut window = SendWindow::new(2);
+    assert!(window.queue(0, data(1), Overflow::Reject));
+    assert!(window.queue(0, data(2), Overflow::Reject));
+    assert_eq!(window.next(0), Some((0, data(1))));
+    assert_eq!(window.next(1), Some((0, data(2))));
+
+    assert!(window.queue(3, data(3), Overflow::Reject));
+    assert_eq!(window.next(2), None);
+
+    // Credit names the highest sequence number received - stale credit changes nothing
+    window.credit(0);
+    assert_eq!(window.next(2), Some((3, data(3))));
+    window.credit(0);
+    assert_eq!(window.next(3), None);
+}
+
+#[test]
+fn full_send_windows_drop_voice_and_reject_bulk() {
+    let mut window = SendWindow::new(2);
+    assert!(window.queue(0, data(1), Overflow::DropOldest));
+    assert!(window.queue(1, data(2), Overflow::Reject));
+
+    assert!(!window.queue(1, data(3), Overflow::Reject));
+    assert!(window.queue(0, data(4), Overflow::DropOldest));
+    assert_eq!(window.dropped(), 1);
+
+    // Only voice cells make room for voice cells
+    assert!(!window.queue(0, data(5), Overflow::Reject));
+    assert_eq!(window.next(0), Some((1, data(2))));
+    assert_eq!(window.next(1), Some((0, data(4))));
+}
+
+#[test]
+fn receive_windows_credit_in_batches() {
+    let mut window = ReceiveWindow::new();
+    for sequence in 0..CREDIT_BATCH - 1 {
+        assert_eq!(window.receive(sequence + 1), None);
+    }
+    // Late cells don't move the credit back
+    assert_eq!(window.receive(0), Some(CREDIT_BATCH - 1));
+}
+
+/** One end of a tunnel as far as its windows are concerned **/
+struct TunnelEnd {
+    sent: SequenceNumbers,
+    window: SendWindow,
+    received: ReceiveWindow
+}
+impl TunnelEnd {
+    fn new() -> TunnelEnd {
+        TunnelEnd {
+            sent: SequenceNumbers::new(),
+            window: SendWindow::new(MIN_SEND_WINDOW),
+            received: ReceiveWindow::new()
+        }
+    }
+
+    /** Takes a numbered cell the way the dialogues do - returns the credit cell sent back, if any **/
+    fn receive(&mut self, sequence: u32, credited: Option<u32>) -> Option<(u32, Option<u32>)> {
+        let reply = self.received.receive(sequence);
+        if let Some(credited) = credited {
+            self.window.credit(credited);
+        }
+        reply.map(|credited| (self.sent.next().unwrap(), Some(credited)))
+    }
+}
+
+#[test]
+fn credit_cells_are_credited_like_any_other_cell() {
+    let (mut initiator, mut destination) = (TunnelEnd::new(), TunnelEnd::new());
+
+    // Only the initiator sends data - the destination's credit uses up its sequence numbers all the same
+    for _ in 0..CREDIT_BATCH * MIN_SEND_WINDOW * 2 {
+        assert!(initiator.window.is_open(initiator.sent.used()));
+        let mut cell = Some((initiator.sent.next().unwrap(), None));
+        let mut towards_destination = true;
+        while let Some((sequence, credited)) = cell {
+            let end = if towards_destination { &mut destination } else { &mut initiator };
+            cell = end.receive(sequence, credited);
+            towards_destination = !towards_destination;
+        }
+    }
+
+    assert!(destination.window.is_open(destination.sent.used()));
+}
+
+#[test]
+fn token_buckets_hold_back_traffic_over_the_rate() {
+    let mut bucket = TokenBucket::new(10);
+    assert!(bucket.take(10));
+    // Rejected bytes don't use up tokens either
+    assert!(!bucket.take(5));
+    assert!(!bucket.take(5));
+
+    let mut unlimited = TokenBucket::new(0);
+    assert!(unlimited.take(1 << 40));
+}
+
+#[test]
+fn token_buckets_are_only_charged_for_what_they_let_through() {
+    let mut bucket = TokenBucket::new(10);
+    assert!(bucket.allows(10));
+    assert!(bucket.allows(10));
+    assert!(bucket.take(10));
+    assert!(!bucket.allows(1));
+}
+
+#[test]
+fn token_buckets_keep_fractions_of_a_token() {
+    let mut bucket = TokenBucket::new(1000);
+    assert!(bucket.take(1000));
+
+    // Every check refills a token and a half - the halves have to add up rather than get lost
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(100) {
+        thread::sleep(Duration::new(0, 1_500_000));
+        bucket.allows(0);
+    }
+    assert!(bucket.take(95));
+}
+
+#[test]
+fn keepalives_probe_idle_tunnels_and_give_up_on_silent_ones() {
+    let keepalive = Keepalive::new(Duration::from_secs(60), 3);
+    assert!(!keepalive.is_due());
+    assert!(!keepalive.is_dead());
+
+    // Every interval that passes in silence counts as a miss
+    let mut keepalive = Keepalive::new(Duration::from_millis(0), 1);
+    keepalive.probed();
+    keepalive.heard();
+    assert!(keepalive.is_due());
+    assert!(keepalive.is_dead());
+}
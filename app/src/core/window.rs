@@ -0,0 +1,106 @@
+// This module is responsible for keeping a tunnel's cells in flight within what the far end can take
+use std::collections::VecDeque;
+
+use messages::p2p::P2PMessage;
+
+// The receiving end hands out credit once this many cells arrived
+pub const CREDIT_BATCH: u32 = 16;
+// Leaves room for cells lost on the way - a window of a single batch stalls on the first lost cell
+pub const MIN_SEND_WINDOW: u32 = 4 * CREDIT_BATCH;
+
+/** What happens to a stream's cells while the tunnel's send window is full and the backlog is too **/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /** Voice - a late cell is worth less than a fresh one **/
+    DropOldest,
+    /** Bulk - the API client is told to back off **/
+    Reject
+}
+
+/**
+    Cells of one direction of a tunnel which the far end hasn't confirmed yet
+    Credit names the highest sequence number received rather than a cell count, so cells lost on the way
+    don't shrink the window for good. Cells which don't fit wait in a backlog of the window's size
+**/
+pub struct SendWindow {
+    size: u64,
+    acknowledged: u64,
+    backlog: VecDeque<(u16, P2PMessage, Overflow)>,
+    dropped: u64
+}
+impl SendWindow {
+    pub fn new(size: u32) -> SendWindow {
+        SendWindow {
+            size: u64::from(size),
+            acknowledged: 0,
+            backlog: VecDeque::new(),
+            dropped: 0
+        }
+    }
+
+    /** Whether another cell may be sent with `sent` sequence numbers used up so far **/
+    pub fn is_open(&self, sent: u64) -> bool {
+        sent < self.acknowledged + self.size
+    }
+
+    /** Returns false if the cell was rejected - voice cells push out the oldest queued voice cell instead **/
+    pub fn queue(&mut self, stream_id: u16, message: P2PMessage, overflow: Overflow) -> bool {
+        if self.backlog.len() as u64 >= self.size {
+            let oldest_voice = self.backlog.iter().position(|&(_, _, queued)| queued == Overflow::DropOldest);
+            match (overflow, oldest_voice) {
+                (Overflow::DropOldest, Some(position)) => {
+                    self.backlog.remove(position);
+                    self.dropped += 1;
+                },
+                _ => return false
+            }
+        }
+
+        self.backlog.push_back((stream_id, message, overflow));
+        true
+    }
+
+    /** The next queued cell if the window lets it through **/
+    pub fn next(&mut self, sent: u64) -> Option<(u16, P2PMessage)> {
+        if !self.is_open(sent) {
+            return None;
+        }
+        self.backlog.pop_front().map(|(stream_id, message, _)| (stream_id, message))
+    }
+
+    /** The far end received every cell up to the sequence number **/
+    pub fn credit(&mut self, sequence: u32) {
+        self.acknowledged = ::std::cmp::max(self.acknowledged, u64::from(sequence) + 1);
+    }
+
+    /** Amount of voice cells dropped while the tunnel was congested **/
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/** Keeps track of the cells received from the far end to hand out credit in batches **/
+pub struct ReceiveWindow {
+    highest: Option<u32>,
+    uncredited: u32
+}
+impl ReceiveWindow {
+    pub fn new() -> ReceiveWindow {
+        ReceiveWindow {
+            highest: None,
+            uncredited: 0
+        }
+    }
+
+    /** Returns the sequence number to credit once a batch of cells arrived **/
+    pub fn receive(&mut self, sequence: u32) -> Option<u32> {
+        self.highest = Some(self.highest.map_or(sequence, |highest| ::std::cmp::max(highest, sequence)));
+        self.uncredited += 1;
+
+        if self.uncredited < CREDIT_BATCH {
+            return None;
+        }
+        self.uncredited = 0;
+        self.highest
+    }
+}
@@ -1,9 +1,80 @@
-error_chain!{}
+use messages::MessageId;
+
+error_chain! {
+    errors {
+        Decode(reason: String) {
+            description("message could not be decoded")
+            display("message could not be decoded - {}", reason)
+        }
+        Encode(reason: String) {
+            description("message could not be encoded")
+            display("message could not be encoded - {}", reason)
+        }
+        Io(action: String) {
+            description("i/o operation failed")
+            display("{} failed", action)
+        }
+        Timeout(waiting_for: String) {
+            description("timed out")
+            display("timed out waiting for {}", waiting_for)
+        }
+        AuthFailure(request_id: u32) {
+            description("auth module rejected a request")
+            display("auth module rejected request {}", request_id)
+        }
+        RpsFailure(reason: String) {
+            description("rps module failed to provide a peer")
+            display("rps module failed to provide a peer - {}", reason)
+        }
+        ProtocolBreach(expected: String, received: String) {
+            description("protocol breach")
+            display("protocol breach - expected {} but received {}", expected, received)
+        }
+        Incompatible(reason: String) {
+            description("peer speaks an incompatible protocol")
+            display("peer speaks an incompatible protocol - {}", reason)
+        }
+        Tampered(reason: String) {
+            description("cell failed its integrity check")
+            display("cell failed its integrity check - {}", reason)
+        }
+        Config(property: String, reason: String) {
+            description("invalid configuration")
+            display("[{}] {}", property, reason)
+        }
+    }
+}
+
+impl Error {
+    /** Whether trying again (e.g. with a different peer) has a chance of succeeding **/
+    pub fn is_retryable(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Io(..) | ErrorKind::Timeout(..) | ErrorKind::RpsFailure(..) |
+                ErrorKind::Incompatible(..) => true,
+            _ => false
+        }
+    }
+
+    /**
+        The request type an `OnionError` reporting this error should carry
+        Module failures can only happen while a tunnel is being built, everything else
+        is attributed to the API request that was being processed
+    **/
+    pub fn request_type(&self, in_flight: MessageId) -> MessageId {
+        match *self.kind() {
+            ErrorKind::RpsFailure(..) => MessageId::OnionTunnelBuild,
+            ErrorKind::AuthFailure(..) if in_flight != MessageId::OnionTunnelData
+                => MessageId::OnionTunnelBuild,
+            _ => in_flight
+        }
+    }
+}
 
 /** Pretty-prints current app status **/
+#[macro_export]
 macro_rules! status {
     ($msg:expr, $type:expr) => {{
-        use colored::*;
+        use $crate::colored::*;
         let color = match $type.as_ref() {
             "msg" => { "green" }
             "error" => { "red" }
@@ -17,21 +88,23 @@ macro_rules! status {
     }}
 }
 /** Pretty-prints sub-status (to be nested under status)**/
+#[macro_export]
 macro_rules! note {
     ($msg:expr) => {{
-        use colored::*;
+        use $crate::colored::*;
         info!(" {}  {}", "→".dimmed() , $msg.dimmed());
     }}
 }
 /** Pretty-prints an error traceback **/
+#[macro_export]
 macro_rules! trace_error {
     ($($b:tt)*) => {
-        || -> Result<()> {
+        || -> $crate::errors::Result<()> {
             $($b)*;
             Ok(())
         }().unwrap_or_else(|e| {
-            use colored::*;
-            use case::CaseExt;
+            use $crate::colored::*;
+            use $crate::case::CaseExt;
 
             let e = &e;
             warn!("{} {}", "Problem:".yellow(), format!("{}", e).to_capitalized().yellow());
@@ -42,20 +115,22 @@ macro_rules! trace_error {
     }
 }
 /** Adds the provided error message to error chain and calls `trace_error` **/
+#[macro_export]
 macro_rules! trace_labeled_error {
     ($msg:expr, $($b:tt)*) => {
         trace_error! {
-            || -> Result<()> {
+            $crate::errors::ResultExt::chain_err(|| -> $crate::errors::Result<()> {
                 $($b)*;
                 Ok(())
-            }().chain_err(|| $msg)?;
+            }(), || $msg)?;
         };
     }
 }
 /** Wrapper around error chain's result which panics on error **/
+#[macro_export]
 macro_rules! trace_panic {
     ( $($b:tt)* ) => {
-        || -> Result<()> {
+        || -> $crate::errors::Result<()> {
             $($b)*;
             Ok(())
         }().unwrap_or_else(|e| {
@@ -64,13 +139,14 @@ macro_rules! trace_panic {
     }
 }
 /** Adds the provided error message to error chain and calls `trace_panic` **/
+#[macro_export]
 macro_rules! trace_labeled_panic {
     ($msg:expr, $($b:tt)*) => {
         trace_panic! {
-            || -> Result<()> {
+            $crate::errors::ResultExt::chain_err(|| -> $crate::errors::Result<()> {
                 $($b)*;
                 Ok(())
-            }().chain_err(|| $msg)?;
+            }(), || $msg)?;
         };
     }
 }
@@ -0,0 +1,45 @@
+#![allow(unknown_lints)]
+#![allow(too_many_arguments)]
+#![allow(dead_code)]
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate error_chain;
+// Re-exported for the exported macros, so applications don't need to depend on them
+#[doc(hidden)]
+pub extern crate colored;
+extern crate stoppable_thread;
+extern crate mio;
+#[doc(hidden)]
+pub extern crate case;
+extern crate bytes;
+extern crate byteorder;
+#[macro_use]
+extern crate structure;
+extern crate bit_field;
+#[macro_use]
+extern crate enum_primitive;
+extern crate num;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate rmp_serde as rmps;
+extern crate time;
+#[macro_use]
+extern crate lazy_static;
+extern crate atty;
+
+// Public modules - usable by tools and applications embedding a node
+#[macro_use]
+pub mod errors;
+pub mod logger;
+pub mod config;
+pub mod messages;
+pub mod node;
+
+// Internal modules
+mod brunch;
+mod core;
+
+pub use node::{Node, NodeHandle, Controller};
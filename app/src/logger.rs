@@ -0,0 +1,246 @@
+// This module is responsible for setting up the app's logger
+use log;
+use log::{Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+use colored;
+use atty;
+use time;
+
+use std::collections::HashSet;
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use errors::*;
+use config;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    Terminal,
+    File,
+    Both
+}
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(target: &str) -> Result<Target> {
+        Ok(match target.to_lowercase().as_ref() {
+            "terminal" => Target::Terminal,
+            "file" => Target::File,
+            "both" => Target::Both,
+            _ => bail!("log target {} unknown - expected terminal, file or both", target)
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Plain,
+    Json
+}
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Format> {
+        Ok(match format.to_lowercase().as_ref() {
+            "plain" => Format::Plain,
+            "json" => Format::Json,
+            _ => bail!("log format {} unknown - expected plain or json", format)
+        })
+    }
+}
+
+pub enum Direction {
+    Incomming,
+    Outgoing
+}
+
+/** A message passing through the app - logged with all of its context **/
+pub struct Traffic<'a> {
+    pub tunnel_id: Option<u32>,
+    pub peer: Option<SocketAddr>,
+    pub message_type: &'a str,
+    pub direction: Direction
+}
+
+struct Output {
+    terminal: bool,
+    file: Option<File>,
+    format: Format
+}
+impl Output {
+    fn write_line(&mut self, line: &str) {
+        // There is nowhere left to report a failing logger to
+        if self.terminal {
+            let _ = writeln!(io::stdout(), "{}", line);
+        }
+        if let Some(ref mut file) = self.file {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+lazy_static! {
+    static ref MAX_LOG_LEVEL: Mutex<Option<MaxLogLevelFilter>> = Mutex::new(None);
+    static ref OUTPUT: Mutex<Output> = Mutex::new(Output {
+        terminal: true,
+        file: None,
+        format: Format::Plain
+    });
+    static ref TRACED_TUNNELS: RwLock<HashSet<u32>> = RwLock::new(HashSet::new());
+}
+
+struct Logger;
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= log::max_log_level()
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut output = OUTPUT.lock().unwrap();
+        let line = match output.format {
+            Format::Plain => format_plain(record.level(), &format!("{}", record.args())),
+            Format::Json => {
+                let mut line = json_head(record.level());
+                json_field(&mut line, "target", record.target());
+                json_field(&mut line, "message", &format!("{}", record.args()));
+                line.push('}');
+                line
+            }
+        };
+        output.write_line(&line);
+    }
+}
+
+fn format_plain(level: LogLevel, message: &str) -> String {
+    if level <= LogLevel::Warn {
+        let now = time::now();
+        format!("{:02}:{:02}:{:02} {}", now.tm_hour, now.tm_min, now.tm_sec, message)
+    } else {
+        message.to_string()
+    }
+}
+
+fn json_head(level: LogLevel) -> String {
+    let mut line = String::from("{");
+    json_field(&mut line, "time", &format!("{}", time::now_utc().rfc3339()));
+    json_field(&mut line, "level", &format!("{}", level));
+    line
+}
+
+fn json_field(line: &mut String, key: &str, value: &str) {
+    if !line.ends_with('{') {
+        line.push(',');
+    }
+    let _ = write!(line, "\"{}\":\"", key);
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(line, "\\u{:04x}", c as u32); },
+            c => line.push(c)
+        }
+    }
+    line.push('"');
+}
+
+/** Initializes the terminal logger and keeps hold of its level so it can be changed later on **/
+pub fn init(level: LogLevelFilter) -> Result<()> {
+    log::set_logger(|max_log_level| {
+        max_log_level.set(level);
+        *MAX_LOG_LEVEL.lock().unwrap() = Some(max_log_level);
+        Box::new(Logger)
+    }).chain_err(|| "failed to initialize logger")
+}
+
+/** Changes the level of the already running logger **/
+pub fn set_level(level: LogLevelFilter) -> Result<()> {
+    match *MAX_LOG_LEVEL.lock().unwrap() {
+        Some(ref max_log_level) => max_log_level.set(level),
+        None => bail!("logger has not been initialized yet")
+    };
+    Ok(())
+}
+
+/** Applies the logging section of the configuration - can be called again on reload **/
+pub fn configure(conf: &config::Config) -> Result<()> {
+    let file = match conf.log_target {
+        Target::Terminal => None,
+        Target::File | Target::Both => Some(OpenOptions::new().create(true).append(true)
+            .open(&conf.log_file)
+            .chain_err(|| format!("couldn't open log file {}", conf.log_file))?)
+    };
+
+    {
+        let mut output = OUTPUT.lock().unwrap();
+        output.terminal = conf.log_target != Target::File;
+        output.file = file;
+        output.format = conf.log_format;
+    }
+
+    // Escape codes only make sense on a plain terminal - not when stdout is piped into a file or another program
+    colored::control::set_override(conf.log_target == Target::Terminal && conf.log_format == Format::Plain &&
+        atty::is(atty::Stream::Stdout));
+
+    *TRACED_TUNNELS.write().unwrap() = conf.trace_tunnels.iter().cloned().collect();
+
+    set_level(conf.log_level)
+}
+
+/**
+    Logs a message passing through the app on the debug level
+    Tunnels marked for tracing are always logged, regardless of the current level
+**/
+pub fn traffic(traffic: Traffic) {
+    let traced = traffic.tunnel_id
+        .map_or(false, |tunnel_id| TRACED_TUNNELS.read().unwrap().contains(&tunnel_id));
+
+    if !traced && LogLevel::Debug > log::max_log_level() {
+        return;
+    }
+    let level = if traced { LogLevel::Trace } else { LogLevel::Debug };
+
+    let mut output = OUTPUT.lock().unwrap();
+    let line = match output.format {
+        Format::Plain => {
+            let mut line = match traffic.direction {
+                Direction::Incomming => format!(" ← {}", traffic.message_type),
+                Direction::Outgoing => format!(" → {}", traffic.message_type)
+            };
+            if let Some(tunnel_id) = traffic.tunnel_id {
+                let _ = write!(line, " [tunnel {}]", tunnel_id);
+            }
+            if let Some(peer) = traffic.peer {
+                let _ = write!(line, " [peer {}]", peer);
+            }
+            format_plain(level, &line)
+        },
+        Format::Json => {
+            let mut line = json_head(level);
+            if let Some(tunnel_id) = traffic.tunnel_id {
+                let _ = write!(line, ",\"tunnel_id\":{}", tunnel_id);
+            }
+            if let Some(peer) = traffic.peer {
+                json_field(&mut line, "peer", &format!("{}", peer));
+            }
+            json_field(&mut line, "message_type", traffic.message_type);
+            json_field(&mut line, "direction", match traffic.direction {
+                Direction::Incomming => "incoming",
+                Direction::Outgoing => "outgoing"
+            });
+            line.push('}');
+            line
+        }
+    };
+    output.write_line(&line);
+}
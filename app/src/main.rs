@@ -1,48 +1,26 @@
 #![allow(unknown_lints)]
-#![allow(too_many_arguments)]
-#![allow(dead_code)]
-#![feature(conservative_impl_trait)]
 
-extern crate simplelog;
 #[macro_use]
 extern crate log;
 extern crate getopts;
-#[macro_use]
-extern crate error_chain;
 extern crate colored;
-extern crate stoppable_thread;
-extern crate mio;
 extern crate case;
-extern crate bytes;
-extern crate byteorder;
-#[macro_use]
-extern crate structure;
-extern crate bit_field;
+extern crate chan;
+extern crate chan_signal;
 #[macro_use]
-extern crate enum_primitive;
-extern crate num;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-extern crate rmp_serde as rmps;
-
-// Required modules
-#[macro_use]
-mod errors;
-mod config;
-mod messages;
-mod brunch;
-mod core;
+extern crate garlic;
 
 // This is the import order for all modules
 // Crate Imports
 use getopts::Options;
-use simplelog::{CombinedLogger, TermLogger};
+use chan_signal::Signal;
+use garlic::{config, logger, Node, Controller};
 // Standard Imports
 use std::env;
 use std::panic;
+use std::thread;
 // Custom Imports
-use errors::*;
+use garlic::errors::*;
 
 /** Specification of cmd arguments **/
 fn parse_cmd_arguments() -> Result<getopts::Matches> {
@@ -53,7 +31,24 @@ fn parse_cmd_arguments() -> Result<getopts::Matches> {
     opts.parse(&args[1..]).chain_err(|| "couldn't parse arguments")
 }
 
-/** Eval cmd arguments and initialize methods, bootstrap brunch **/
+/** Translates process signals into node controls **/
+fn forward_signals(signals: chan::Receiver<Signal>, controller: Controller) {
+    thread::spawn(move || {
+        while let Some(signal) = signals.recv() {
+            let result = match signal {
+                Signal::HUP => controller.reload(),
+                Signal::INT | Signal::TERM => controller.shutdown(),
+                _ => continue
+            };
+
+            if result.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/** Eval cmd arguments and initialize methods, start the node **/
 fn bootstrap() -> Result<()> {
     let arguments = parse_cmd_arguments()?;
 
@@ -62,7 +57,14 @@ fn bootstrap() -> Result<()> {
     let conf = config::read_config_file(config_file_path)
         .chain_err(|| "couldn't create configuration struct")?;
 
-    brunch::start(conf)
+    logger::configure(&conf)?;
+
+    // Has to happen before any threads are spawned so they inherit the blocked signals
+    let signals = chan_signal::notify(&[Signal::HUP, Signal::INT, Signal::TERM]);
+
+    let node = Node::start(conf)?;
+    forward_signals(signals, node.controller());
+    node.wait()
 }
 
 /** Setup logger and boostrap the app **/
@@ -88,14 +90,9 @@ fn main() {
         ::std::process::exit(2);
     }));
 
-    CombinedLogger::init(
-        vec![
-            TermLogger::new(log::LogLevelFilter::Info, simplelog::Config {
-                time: Some(log::LogLevel::Warn),
-                level: None, target: None, location: None
-            }).expect("Failed to initialize terminal logger")
-        ]
-    ).expect("Failed to initialize logger");
+    logger::init(log::LogLevelFilter::Info).expect("Failed to initialize logger");
 
     trace_panic! { bootstrap()? };
+
+    status!("Shut down cleanly");
 }
@@ -1,117 +1,203 @@
 use errors::*;
+use messages::{MessageId, WireFormat, WireMessage};
 
 use bit_field::BitField;
+use bytes::{Bytes, BytesMut};
 
+#[derive(Debug, PartialEq)]
 pub struct AuthSessionStart {
     pub request_id: u32,
-    pub hostkey: Vec<u8>
+    pub hostkey: Bytes
 }
 /* 4B Reserved | 4B RequestId | Rest Hostkey */
-impl AuthSessionStart {
-    pub fn encode(self) -> Result<Vec<u8>> {
-        let mut bytes = pack_structure!("4xI", self.request_id);
-        bytes.extend_from_slice(&self.hostkey);
-        Ok(bytes)
+impl WireFormat for AuthSessionStart {
+    fn decode(bytes: Bytes) -> Result<AuthSessionStart> {
+        ensure_length!(bytes, 8);
+        let (reserved, request_id) = unpack_structure!("II", &bytes[0..8]);
+        ensure_reserved!(reserved);
+        Ok(AuthSessionStart {
+            request_id: request_id,
+            hostkey: bytes.slice_from(8)
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "4xI", self.request_id);
+        buffer.extend_from_slice(&self.hostkey);
+        Ok(())
     }
 }
+impl WireMessage for AuthSessionStart {
+    const ID: MessageId = MessageId::AuthSessionStart;
+}
 
+#[derive(Debug, PartialEq)]
 pub struct AuthSessionHS {
     pub session_id: u16,
     pub request_id: u32,
-    pub payload: Vec<u8>
+    pub payload: Bytes
 }
 /* 2B Reserved | 2B SessionId | 4B RequestId | Rest Payload */
-impl AuthSessionHS {
-    pub fn decode(bytes: Vec<u8>) -> Result<AuthSessionHS> {
-        let (session_id, request_id) = unpack_structure!("2xHI", &bytes[0..8]);
+impl WireFormat for AuthSessionHS {
+    fn decode(bytes: Bytes) -> Result<AuthSessionHS> {
+        ensure_length!(bytes, 8);
+        let (reserved, session_id, request_id) = unpack_structure!("HHI", &bytes[0..8]);
+        ensure_reserved!(reserved);
         Ok(AuthSessionHS {
             session_id: session_id,
             request_id: request_id,
-            payload: bytes[8..].to_vec()
+            payload: bytes.slice_from(8)
         })
     }
-    pub fn encode(self) -> Result<Vec<u8>> {
-        let mut bytes = pack_structure!("2xHI", self.session_id, self.request_id);
-        bytes.extend_from_slice(&self.payload);
-        Ok(bytes)
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "2xHI", self.session_id, self.request_id);
+        buffer.extend_from_slice(&self.payload);
+        Ok(())
     }
 }
+shared_layout!(AuthSessionHS: AuthSessionHS1, AuthSessionHS2, AuthSessionIncommingHS2);
 
+#[derive(Debug, PartialEq)]
 pub struct AuthSessionHS1Response {
     pub request_id: u32,
-    pub payload: Vec<u8>
+    pub payload: Bytes
 }
-impl AuthSessionHS1Response {
-    pub fn encode(self) -> Result<Vec<u8>> {
-        let mut bytes = pack_structure!("4xI", self.request_id);
-        bytes.extend_from_slice(&self.payload);
-        Ok(bytes)
+/* 4B Reserved | 4B RequestId | Rest Payload */
+impl WireFormat for AuthSessionHS1Response {
+    fn decode(bytes: Bytes) -> Result<AuthSessionHS1Response> {
+        ensure_length!(bytes, 8);
+        let (reserved, request_id) = unpack_structure!("II", &bytes[0..8]);
+        ensure_reserved!(reserved);
+        Ok(AuthSessionHS1Response {
+            request_id: request_id,
+            payload: bytes.slice_from(8)
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "4xI", self.request_id);
+        buffer.extend_from_slice(&self.payload);
+        Ok(())
     }
 }
+impl WireMessage for AuthSessionHS1Response {
+    const ID: MessageId = MessageId::AuthSessionIncommingHS1;
+}
 
+#[derive(Debug, PartialEq)]
 pub struct AuthCipherCrypt {
     pub session_id: u16,
     pub request_id: u32,
     pub cleartext: bool,
-    pub payload: Vec<u8>
+    pub payload: Bytes
 }
-impl AuthCipherCrypt {
-    pub fn encode(self) -> Result<Vec<u8>> {
-        let mut bytes = pack_structure!("3xBIH", boolean!(self.cleartext), self.request_id, self.session_id);
-        bytes.extend_from_slice(&self.payload);
-        Ok(bytes)
+/* 3B Reserved | 7b1b Cleartext | 4B RequestId | 2B SessionId | Rest Payload */
+impl WireFormat for AuthCipherCrypt {
+    fn decode(bytes: Bytes) -> Result<AuthCipherCrypt> {
+        ensure_length!(bytes, 10);
+        let (reserved, reserved_byte, cleartext, request_id, session_id) =
+            unpack_structure!("HBBIH", &bytes[0..10]);
+        ensure_reserved!(reserved);
+        ensure_reserved!(reserved_byte);
+        ensure_reserved!(cleartext & !0b1);
+        Ok(AuthCipherCrypt {
+            session_id: session_id,
+            request_id: request_id,
+            cleartext: cleartext.get_bit(0),
+            payload: bytes.slice_from(10)
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "3xBIH", boolean!(self.cleartext), self.request_id, self.session_id);
+        buffer.extend_from_slice(&self.payload);
+        Ok(())
     }
 }
+shared_layout!(AuthCipherCrypt: AuthCipherEncrypt, AuthCipherDecrypt);
 
+#[derive(Debug, PartialEq)]
 pub struct AuthCipherCryptResp {
     pub request_id: u32,
     pub cleartext: bool,
-    pub payload: Vec<u8>
+    pub payload: Bytes
 }
 /* 3B Reserved | 7b1b Cleartext | 4B RequestId | Rest Payload */
-impl AuthCipherCryptResp {
-    pub fn decode(bytes: Vec<u8>) -> Result<AuthCipherCryptResp> {
-        let (cleartext, request_id,) = unpack_structure!("3xBI", &bytes);
+impl WireFormat for AuthCipherCryptResp {
+    fn decode(bytes: Bytes) -> Result<AuthCipherCryptResp> {
+        ensure_length!(bytes, 8);
+        let (reserved, reserved_byte, cleartext, request_id) = unpack_structure!("HBBI", &bytes[0..8]);
+        ensure_reserved!(reserved);
+        ensure_reserved!(reserved_byte);
+        ensure_reserved!(cleartext & !0b1);
         Ok(AuthCipherCryptResp {
             request_id: request_id,
             cleartext: cleartext.get_bit(0),
-            payload: bytes[8..].to_vec()
+            payload: bytes.slice_from(8)
         })
     }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "3xBI", boolean!(self.cleartext), self.request_id);
+        buffer.extend_from_slice(&self.payload);
+        Ok(())
+    }
 }
+shared_layout!(AuthCipherCryptResp: AuthCipherEncryptResp, AuthCipherDecryptResp);
 
+#[derive(Debug, PartialEq)]
 pub struct AuthSessionClose {
     pub session_id: u16
 }
-impl AuthSessionClose {
-    pub fn encode(self) -> Result<Vec<u8>> {
-        Ok(pack_structure!("2xH", self.session_id))
+/* 2B Reserved | 2B SessionId */
+impl WireFormat for AuthSessionClose {
+    fn decode(bytes: Bytes) -> Result<AuthSessionClose> {
+        ensure_length!(exactly bytes, 4);
+        let (reserved, session_id) = unpack_structure!("HH", &bytes);
+        ensure_reserved!(reserved);
+        Ok(AuthSessionClose {
+            session_id: session_id
+        })
     }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "2xH", self.session_id);
+        Ok(())
+    }
+}
+impl WireMessage for AuthSessionClose {
+    const ID: MessageId = MessageId::AuthSessionClose;
 }
 
+#[derive(Debug, PartialEq)]
 pub struct AuthSessionError {
     pub request_id: u32
 }
 /* 4B Reserved | 4B RequestId */
-impl AuthSessionError {
-    pub fn decode(bytes: Vec<u8>) -> Result<AuthSessionError> {
-        let (request_id,) = unpack_structure!("4xI", &bytes);
+impl WireFormat for AuthSessionError {
+    fn decode(bytes: Bytes) -> Result<AuthSessionError> {
+        ensure_length!(exactly bytes, 8);
+        let (reserved, request_id) = unpack_structure!("II", &bytes);
+        ensure_reserved!(reserved);
         Ok(AuthSessionError {
             request_id: request_id
         })
     }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "4xI", self.request_id);
+        Ok(())
+    }
+}
+impl WireMessage for AuthSessionError {
+    const ID: MessageId = MessageId::AuthSessionError;
 }
 
+#[derive(Debug, PartialEq)]
 pub enum Auth {
     SessionStart(AuthSessionStart),
-    SessionHS1(AuthSessionHS),
+    SessionHS1(AuthSessionHS1),
     SessionIncommingHS1(AuthSessionHS1Response),
-    SessionHS2(AuthSessionHS),
-    SessionIncommingHS2(AuthSessionHS),
-    CipherEncrypt(AuthCipherCrypt),
-    CipherEncryptResp(AuthCipherCryptResp),
-    CipherDecrypt(AuthCipherCrypt),
-    CipherDecryptResp(AuthCipherCryptResp),
+    SessionHS2(AuthSessionHS2),
+    SessionIncommingHS2(AuthSessionIncommingHS2),
+    CipherEncrypt(AuthCipherEncrypt),
+    CipherEncryptResp(AuthCipherEncryptResp),
+    CipherDecrypt(AuthCipherDecrypt),
+    CipherDecryptResp(AuthCipherDecryptResp),
     SessionClose(AuthSessionClose),
     SessionError(AuthSessionError)
 }
@@ -0,0 +1,102 @@
+use errors::*;
+
+use bytes::{Bytes, BytesMut};
+
+use messages::WireFormat;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher, SipHasher};
+
+// Marks a layer whose payload is the next hop's layer rather than data for the hop peeling it
+const RELAYED: u16 = 1;
+
+/**
+    Plaintext of a single onion layer - every layer of a cell carries the same sequence number
+    so each hop as well as the far end can drop replayed cells on its own
+    Recognised layers carry data for the hop peeling them, all others have to be forwarded
+    Only recognised layers belong to a stream - stream 0 is the tunnel itself
+    The digest covers the whole layer and is keyed per session, tampered layers fail to decode
+**/
+#[derive(Debug, PartialEq)]
+pub struct Cell {
+    pub recognised: bool,
+    pub stream_id: u16,
+    pub sequence: u32,
+    pub payload: Bytes
+}
+/* 2B Recognised | 2B StreamId | 4B Digest | 4B Sequence | Rest Payload */
+impl Cell {
+    pub fn decode(bytes: Bytes, key: &DigestKey) -> Result<Cell> {
+        ensure_length!(bytes, 12);
+        let (recognised, stream_id, digest, sequence) = unpack_structure!("HHII", &bytes[0..12]);
+        if digest != key.digest(&[&bytes[0..4], &bytes[8..]]) {
+            bail!(ErrorKind::Tampered("digest does not match".to_string()));
+        }
+
+        let recognised = match recognised {
+            0 => true,
+            RELAYED => false,
+            _ => bail!(ErrorKind::Tampered(format!("unknown recognised field {:#x}", recognised)))
+        };
+        if !recognised && stream_id != 0 {
+            bail!(ErrorKind::Tampered(format!("relayed layer claims to belong to stream {}", stream_id)));
+        }
+
+        Ok(Cell {
+            recognised: recognised,
+            stream_id: stream_id,
+            sequence: sequence,
+            payload: bytes.slice_from(12)
+        })
+    }
+    pub fn encode(self, key: &DigestKey, buffer: &mut BytesMut) -> Result<()> {
+        let head = pack_structure!("HH", if self.recognised { 0 } else { RELAYED }, self.stream_id);
+        let tail = pack_structure!("I", self.sequence);
+        let digest = key.digest(&[&head, &tail, &self.payload]);
+
+        buffer.extend_from_slice(&head);
+        write_structure!(buffer, "I", digest);
+        buffer.extend_from_slice(&tail);
+        buffer.extend_from_slice(&self.payload);
+        Ok(())
+    }
+}
+
+/**
+    Keys the SipHash-2-4 digest of every layer wrapped with one Auth session
+    The initiator picks it at random and hands it to the hop encrypted with the session it keys - so only
+    the two ends of the session can wrap a layer which passes its digest
+**/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DigestKey(u64, u64);
+impl DigestKey {
+    /** Hashers built from a fresh `RandomState` are keyed with the process' random seed **/
+    pub fn random() -> DigestKey {
+        let mut hasher = RandomState::new().build_hasher();
+        let k0 = hasher.finish();
+        hasher.write_u64(k0);
+        DigestKey(k0, hasher.finish())
+    }
+
+    /** Digest of the layer with its digest field left out **/
+    #[allow(deprecated)]
+    fn digest(&self, parts: &[&[u8]]) -> u32 {
+        let mut hasher = SipHasher::new_with_keys(self.0, self.1);
+        for part in parts {
+            hasher.write(part);
+        }
+        hasher.finish() as u32
+    }
+}
+/* 8B K0 | 8B K1 */
+impl WireFormat for DigestKey {
+    fn decode(bytes: Bytes) -> Result<DigestKey> {
+        ensure_length!(exactly bytes, 16);
+        let (k0, k1) = unpack_structure!("QQ", &bytes);
+        Ok(DigestKey(k0, k1))
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "QQ", self.0, self.1);
+        Ok(())
+    }
+}
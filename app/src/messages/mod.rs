@@ -4,26 +4,47 @@ pub mod onion;
 pub mod auth;
 pub mod rps;
 pub mod p2p;
+pub mod cell;
+#[cfg(test)]
+mod tests;
 
 use errors::*;
 use messages::auth::*;
 use messages::onion::*;
 use messages::rps::*;
-use messages::p2p::P2PMessage;
 
+use bytes::{Bytes, BytesMut};
 use num::FromPrimitive;
-use serde::{Deserialize, Serialize};
-use rmps::{Deserializer, Serializer};
 
+const HEADER_LENGTH: usize = 4;
+
+/**
+    Wire format of a single body - layouts shared by several messages and the P2P formats only implement this
+    Decoded payloads are slices sharing the received frame, encoding appends to a single buffer
+**/
+pub trait WireFormat: Sized {
+    fn decode(bytes: Bytes) -> Result<Self>;
+    fn encode(self, buffer: &mut BytesMut) -> Result<()>;
+}
+
+/**
+    Implemented by every API message struct - the id is the one carried in its header
+    Ids sharing a layout each get a newtype around it (see `shared_layout!`)
+**/
+pub trait WireMessage: WireFormat {
+    const ID: MessageId;
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Message {
     Onion(Onion),
     Auth(Auth),
-    Rps(Rps),
-    P2P(P2PMessage)
+    Rps(Rps)
 }
 
 // Ref: 28028854
 enum_from_primitive! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
     #[repr(u16)]
     pub enum MessageId {
         RpsQuery = 540,
@@ -35,6 +56,9 @@ enum_from_primitive! {
         OnionTunnelData = 564,
         OnionCover = 566,
         OnionError = 565,
+        OnionStreamOpen = 567,
+        OnionStreamClose = 568,
+        OnionStreamData = 569,
         AuthSessionStart = 600,
         AuthSessionHS1 = 601,
         AuthSessionIncommingHS1 = 602,
@@ -49,91 +73,114 @@ enum_from_primitive! {
     }
 }
 
-#[allow(or_fun_call)]
-pub fn decode_message(bytes: &[u8]) -> Result<Message> {
-    use self::Message::*;
-    use messages::auth::Auth::*;
-    use messages::onion::Onion::*;
-    use messages::rps::Rps::*;
-
-    // Quick and dirty hack for current message system
-    let mut deserializer = Deserializer::new(bytes);
-    let p2p_message = Deserialize::deserialize(&mut deserializer);
-
-    if let Ok(p2p_message) = p2p_message {
-        return Ok(Message::P2P(p2p_message));
+/**
+    Ties every message id to the `Message` variant and struct carrying it
+    The header written on encode is the struct's own id - decoding an id into a struct carrying another
+    one is caught by the tests
+    Both dispatchers are exhaustive, so a missing entry fails to compile instead of panicking
+**/
+macro_rules! registry {
+    ($($message_id:ident => $group:ident::$variant:ident($structure:ident)),*) => {
+        impl Message {
+            /** Human readable message type used for logging **/
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    $(Message::$group($group::$variant(_)) => stringify!($message_id),)*
+                }
+            }
+
+            /** Id carried in the header **/
+            pub fn id(&self) -> MessageId {
+                match *self {
+                    $(Message::$group($group::$variant(_)) => <$structure as WireMessage>::ID,)*
+                }
+            }
+        }
+
+        impl MessageId {
+            /** Every id the codec understands - tools can use it to play any role on the wire **/
+            pub fn all() -> &'static [MessageId] {
+                &[$(MessageId::$message_id),*]
+            }
+        }
+
+        fn decode_body(message_id: MessageId, bytes: Bytes) -> Result<Message> {
+            Ok(match message_id {
+                $(MessageId::$message_id => Message::$group($group::$variant(
+                    <$structure as WireFormat>::decode(bytes)?)),)*
+            })
+        }
+
+        fn encode_body(message: Message, buffer: &mut BytesMut) -> Result<MessageId> {
+            Ok(match message {
+                $(Message::$group($group::$variant(message)) => {
+                    message.encode(buffer)?;
+                    <$structure as WireMessage>::ID
+                },)*
+            })
+        }
     }
+}
 
-    let (length, message_type) = unpack_structure!("2H", &bytes[0..4]);
+registry! {
+    OnionTunnelBuild => Onion::TunnelBuild(OnionTunnelBuild),
+    OnionTunnelReady => Onion::TunnelReady(OnionTunnelReady),
+    OnionTunnelIncomming => Onion::TunnelIncomming(OnionTunnelIncomming),
+    OnionTunnelDestroy => Onion::TunnelDestroy(OnionTunnelDestroy),
+    OnionTunnelData => Onion::TunnelData(OnionTunnelData),
+    OnionCover => Onion::Cover(OnionCover),
+    OnionError => Onion::Error(OnionError),
+    OnionStreamOpen => Onion::StreamOpen(OnionStreamOpen),
+    OnionStreamClose => Onion::StreamClose(OnionStreamClose),
+    OnionStreamData => Onion::StreamData(OnionStreamPayload),
+
+    AuthSessionStart => Auth::SessionStart(AuthSessionStart),
+    AuthSessionHS1 => Auth::SessionHS1(AuthSessionHS1),
+    AuthSessionIncommingHS1 => Auth::SessionIncommingHS1(AuthSessionHS1Response),
+    AuthSessionHS2 => Auth::SessionHS2(AuthSessionHS2),
+    AuthSessionIncommingHS2 => Auth::SessionIncommingHS2(AuthSessionIncommingHS2),
+    AuthCipherEncrypt => Auth::CipherEncrypt(AuthCipherEncrypt),
+    AuthCipherEncryptResp => Auth::CipherEncryptResp(AuthCipherEncryptResp),
+    AuthCipherDecrypt => Auth::CipherDecrypt(AuthCipherDecrypt),
+    AuthCipherDecryptResp => Auth::CipherDecryptResp(AuthCipherDecryptResp),
+    AuthSessionClose => Auth::SessionClose(AuthSessionClose),
+    AuthSessionError => Auth::SessionError(AuthSessionError),
+
+    RpsQuery => Rps::Query(RpsQuery),
+    RpsPeer => Rps::Peer(RpsPeer)
+}
+
+#[allow(or_fun_call)]
+pub fn decode_message(bytes: Bytes) -> Result<Message> {
+    ensure_length!(bytes, HEADER_LENGTH);
+    let (length, message_type) = unpack_structure!("2H", &bytes[0..HEADER_LENGTH]);
     let length = length as usize;
 
+    if length < HEADER_LENGTH {
+        bail!(ErrorKind::Decode(format!("message length {} is shorter than its header", length)));
+    }
     if bytes.len() < length {
-        bail!("message length is supposed to be {}, but was {}", length, bytes.len());
+        bail!(ErrorKind::Decode(format!("message length is supposed to be {}, but was {}", length, bytes.len())));
     }
 
-    let bytes = bytes[4..length].to_vec();
-
-    // TODO: This could use some dedupe refactoring (maybe a procedural macro - but that stuff is difficult to write)
-    Ok(match MessageId::from_u16(message_type)
-        .ok_or(::errors::Error::from("conversion of message type failed"))? {
+    let message_id = MessageId::from_u16(message_type)
+        .ok_or(ErrorKind::Decode(format!("message type {} unknown", message_type)))?;
 
-        MessageId::OnionTunnelBuild => Onion(TunnelBuild(OnionTunnelBuild::decode(bytes)?)),
-        MessageId::OnionTunnelReady => Onion(TunnelReady(OnionTunnelPayload::decode(bytes)?)),
-        MessageId::OnionTunnelData => Onion(TunnelData(OnionTunnelPayload::decode(bytes)?)),
-        MessageId::OnionTunnelIncomming => Onion(TunnelIncomming(OnionTunnelID::decode(bytes)?)),
-        MessageId::OnionTunnelDestroy => Onion(TunnelDestroy(OnionTunnelID::decode(bytes)?)),
-        MessageId::OnionCover => Onion(Cover(OnionCover::decode(bytes)?)),
+    decode_body(message_id, bytes.slice(HEADER_LENGTH, length))
+}
 
-        MessageId::AuthSessionHS1 => Auth(SessionHS1(AuthSessionHS::decode(bytes)?)),
-        MessageId::AuthSessionHS2 => Auth(SessionHS2(AuthSessionHS::decode(bytes)?)),
-        MessageId::AuthSessionIncommingHS2 => Auth(SessionIncommingHS2(AuthSessionHS::decode(bytes)?)),
-        MessageId::AuthCipherEncryptResp => Auth(CipherEncryptResp(AuthCipherCryptResp::decode(bytes)?)),
-        MessageId::AuthCipherDecryptResp => Auth(CipherDecryptResp(AuthCipherCryptResp::decode(bytes)?)),
-        MessageId::AuthSessionError => Auth(SessionError(AuthSessionError::decode(bytes)?)),
+pub fn encode_message(message: Message) -> Result<Bytes> {
+    let mut buffer = BytesMut::with_capacity(64);
 
-        MessageId::RpsPeer => Message::Rps(Peer(RpsPeer::decode(bytes)?)),
+    // The header is filled in once the length of the body is known
+    buffer.extend_from_slice(&[0; HEADER_LENGTH]);
+    let message_id = encode_body(message, &mut buffer)?;
 
-        _ => bail!("message type {} unknown", message_type)
-    })
-}
+    let length = buffer.len();
+    if length > u16::max_value() as usize {
+        bail!(ErrorKind::Encode(format!("message length {} exceeds the maximum of {}", length, u16::max_value())));
+    }
+    buffer[0..HEADER_LENGTH].copy_from_slice(&pack_structure!("2H", length as u16, message_id as u16));
 
-pub fn encode_message(message: Message) -> Result<Vec<u8>> {
-    use self::Message::*;
-    use self::MessageId::*;
-    use messages::auth::Auth::*;
-    use messages::onion::Onion::*;
-    use messages::rps::Rps::*;
-
-    // TODO: ↑ + compiler cannot check a correct call to encode which can result in a bail out
-    let (message_id, message) = match message {
-        Onion(TunnelReady(message)) => (OnionTunnelReady, message.encode()?),
-        Onion(TunnelIncomming(message)) => (OnionTunnelIncomming, message.encode()?),
-        Onion(TunnelDestroy(message)) => (OnionTunnelDestroy, message.encode()?),
-        Onion(TunnelData(message)) => (OnionTunnelData, message.encode()?),
-        Onion(Error(message)) => (OnionError, message.encode()?),
-
-        Auth(SessionStart(message)) => (AuthSessionStart, message.encode()?),
-        Auth(SessionHS1(message)) => (AuthSessionHS1, message.encode()?),
-        Auth(SessionIncommingHS1(message)) => (AuthSessionIncommingHS1, message.encode()?),
-        Auth(SessionHS2(message)) => (AuthSessionHS2, message.encode()?),
-        Auth(SessionIncommingHS2(message)) => (AuthSessionIncommingHS2, message.encode()?),
-        Auth(CipherEncrypt(message)) => (AuthCipherEncrypt, message.encode()?),
-        Auth(CipherDecrypt(message)) => (AuthCipherDecrypt, message.encode()?),
-        Auth(SessionClose(message)) => (AuthSessionClose, message.encode()?),
-
-        Rps(Query(message)) => (RpsQuery, message.encode()?),
-        
-        P2P(message) => {
-            let mut bytes = Vec::new();
-            message.serialize(&mut Serializer::new(&mut bytes)).chain_err(|| "couldn't serialize P2P message")?;
-            return Ok(bytes);
-        },
-
-        // BUG: This has to be fixed with a better message system
-        _ => panic!("a call to 'encode' that does not exist on this message type was requested")
-    };
-
-    let mut bytes = pack_structure!("2H", message.len() as u16 + 4, message_id as u16);
-    bytes.extend_from_slice(&message);
-    Ok(bytes)
+    Ok(buffer.freeze())
 }
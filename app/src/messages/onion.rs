@@ -1,101 +1,211 @@
 use errors::*;
 
 use bit_field::BitField;
+use bytes::{Bytes, BytesMut};
 
-use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
+use messages::{MessageId, WireFormat, WireMessage};
+use messages::utilities::{decode_ip_addr, encode_ip_addr, validate_hostkey};
 
+use std::net::IpAddr;
+
+#[derive(Debug, PartialEq)]
 pub struct OnionTunnelBuild {
     pub onion_tunnel: u16,
     pub ip_addr: IpAddr,
-    pub hostkey: Vec<u8>
+    pub hostkey: Bytes
 }
 /* 1B Reserved | 7b1b IPv | 2B OnionTunnel | 16B/4B IP | Rest Hostkey */
-impl OnionTunnelBuild {
-    pub fn decode(bytes: Vec<u8>) -> Result<OnionTunnelBuild> {
-        let (ipv, onion_tunnel) = unpack_structure!("xBH", &bytes);
+impl WireFormat for OnionTunnelBuild {
+    fn decode(bytes: Bytes) -> Result<OnionTunnelBuild> {
+        ensure_length!(bytes, 4);
+        let (reserved, ipv, onion_tunnel) = unpack_structure!("BBH", &bytes[0..4]);
+        ensure_reserved!(reserved);
+        ensure_reserved!(ipv & !0b1);
 
-        let (next_field_offset, ip_addr) = if ipv.get_bit(0) {
-            let (i0, i1, i2, i3, i4, i5, i6, i7) = unpack_structure!("8H", &bytes[4..18]);
-            (18, IpAddr::V6(Ipv6Addr::new(i0, i1, i2, i3, i4, i5, i6, i7)))
-        } else {
-            let (i0, i1, i2, i3) = unpack_structure!("4B", &bytes[4..8]);
-            (8, IpAddr::V4(Ipv4Addr::new(i0, i1, i2, i3)))
-        };
+        let (ip_addr, ip_length) = decode_ip_addr(ipv.get_bit(0), &bytes[4..])?;
+        let next_field_offset = 4 + ip_length;
+        validate_hostkey(&bytes[next_field_offset..])?;
 
         Ok(OnionTunnelBuild {
             onion_tunnel: onion_tunnel,
             ip_addr: ip_addr,
-            hostkey: bytes[next_field_offset..].to_vec()
+            hostkey: bytes.slice_from(next_field_offset)
         })
     }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        validate_hostkey(&self.hostkey)
+            .chain_err(|| ErrorKind::Encode("hostkey is not a well-formed DER key".to_string()))?;
+        let (ipv6, ip_addr) = encode_ip_addr(&self.ip_addr);
+        write_structure!(buffer, "BBH", 0, boolean!(ipv6), self.onion_tunnel);
+        buffer.extend_from_slice(&ip_addr);
+        buffer.extend_from_slice(&self.hostkey);
+        Ok(())
+    }
+}
+impl WireMessage for OnionTunnelBuild {
+    const ID: MessageId = MessageId::OnionTunnelBuild;
 }
 
+#[derive(Debug, PartialEq)]
 pub struct OnionTunnelPayload {
     pub tunnel_id: u32,
-    pub payload: Vec<u8>
+    pub payload: Bytes
 }
 /* 4B TunnelId | Rest Payload */
-impl OnionTunnelPayload {
-    pub fn decode(bytes: Vec<u8>) -> Result<OnionTunnelPayload> {
-        let (tunnel_id,) = unpack_structure!("I", &bytes);
+impl WireFormat for OnionTunnelPayload {
+    fn decode(bytes: Bytes) -> Result<OnionTunnelPayload> {
+        ensure_length!(bytes, 4);
+        let (tunnel_id,) = unpack_structure!("I", &bytes[0..4]);
         Ok(OnionTunnelPayload {
             tunnel_id: tunnel_id,
-            payload: bytes[4..].to_vec()
+            payload: bytes.slice_from(4)
         })
     }
-    pub fn encode(self) -> Result<Vec<u8>> {
-        let mut bytes = pack_structure!("I", self.tunnel_id);
-        bytes.extend_from_slice(&self.payload);
-        Ok(bytes)
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "I", self.tunnel_id);
+        buffer.extend_from_slice(&self.payload);
+        Ok(())
     }
 }
+shared_layout!(OnionTunnelPayload: OnionTunnelReady, OnionTunnelData);
 
+#[derive(Debug, PartialEq)]
 pub struct OnionTunnelID {
     pub tunnel_id: u32
 }
 /* 4B TunnelId */
-impl OnionTunnelID {
-    pub fn decode(bytes: Vec<u8>) -> Result<OnionTunnelID> {
+impl WireFormat for OnionTunnelID {
+    fn decode(bytes: Bytes) -> Result<OnionTunnelID> {
+        ensure_length!(exactly bytes, 4);
         let (tunnel_id,) = unpack_structure!("I", &bytes);
         Ok(OnionTunnelID {
             tunnel_id: tunnel_id
         })
     }
-    pub fn encode(self) -> Result<Vec<u8>> {
-        Ok(pack_structure!("I", self.tunnel_id))
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "I", self.tunnel_id);
+        Ok(())
+    }
+}
+shared_layout!(OnionTunnelID: OnionTunnelIncomming, OnionTunnelDestroy);
+
+/**
+    Stream 0 is the tunnel itself - all others are opened and closed by either end
+    Voice streams drop their oldest cells instead of rejecting new ones while the tunnel is congested,
+    the flag only matters when opening a stream
+**/
+#[derive(Debug, PartialEq)]
+pub struct OnionStream {
+    pub tunnel_id: u32,
+    pub stream_id: u16,
+    pub voice: bool
+}
+/* 4B TunnelId | 2B StreamId | 1B Reserved | 7b1b Voice */
+impl WireFormat for OnionStream {
+    fn decode(bytes: Bytes) -> Result<OnionStream> {
+        ensure_length!(exactly bytes, 8);
+        let (tunnel_id, stream_id, reserved, voice) = unpack_structure!("IHBB", &bytes);
+        ensure_reserved!(reserved);
+        ensure_reserved!(voice & !0b1);
+        Ok(OnionStream {
+            tunnel_id: tunnel_id,
+            stream_id: stream_id,
+            voice: voice.get_bit(0)
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "IHxB", self.tunnel_id, self.stream_id, boolean!(self.voice));
+        Ok(())
     }
 }
+shared_layout!(OnionStream: OnionStreamOpen, OnionStreamClose);
 
+#[derive(Debug, PartialEq)]
+pub struct OnionStreamPayload {
+    pub tunnel_id: u32,
+    pub stream_id: u16,
+    pub payload: Bytes
+}
+/* 4B TunnelId | 2B StreamId | 2B Reserved | Rest Payload */
+impl WireFormat for OnionStreamPayload {
+    fn decode(bytes: Bytes) -> Result<OnionStreamPayload> {
+        ensure_length!(bytes, 8);
+        let (tunnel_id, stream_id, reserved) = unpack_structure!("IHH", &bytes[0..8]);
+        ensure_reserved!(reserved);
+        Ok(OnionStreamPayload {
+            tunnel_id: tunnel_id,
+            stream_id: stream_id,
+            payload: bytes.slice_from(8)
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "IH2x", self.tunnel_id, self.stream_id);
+        buffer.extend_from_slice(&self.payload);
+        Ok(())
+    }
+}
+impl WireMessage for OnionStreamPayload {
+    const ID: MessageId = MessageId::OnionStreamData;
+}
+
+#[derive(Debug, PartialEq)]
 pub struct OnionError {
     pub tunnel_id: u32,
     pub request_type: u16
 }
 /* 2B RequestType | 2B Reserved | 4B TunnelId */
-impl OnionError {
-    pub fn encode(self) -> Result<Vec<u8>> {
-        Ok(pack_structure!("H2xI", self.request_type, self.tunnel_id))
+impl WireFormat for OnionError {
+    fn decode(bytes: Bytes) -> Result<OnionError> {
+        ensure_length!(exactly bytes, 8);
+        let (request_type, reserved, tunnel_id) = unpack_structure!("HHI", &bytes);
+        ensure_reserved!(reserved);
+        Ok(OnionError {
+            tunnel_id: tunnel_id,
+            request_type: request_type
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "H2xI", self.request_type, self.tunnel_id);
+        Ok(())
     }
 }
+impl WireMessage for OnionError {
+    const ID: MessageId = MessageId::OnionError;
+}
 
+#[derive(Debug, PartialEq)]
 pub struct OnionCover {
     pub cover_size: u16,
 }
 /* 2B CoverSize | 2B Reserved */
-impl OnionCover {
-    pub fn decode(bytes: Vec<u8>) -> Result<OnionCover> {
-        let (cover_size,) = unpack_structure!("H2x", &bytes);
+impl WireFormat for OnionCover {
+    fn decode(bytes: Bytes) -> Result<OnionCover> {
+        ensure_length!(exactly bytes, 4);
+        let (cover_size, reserved) = unpack_structure!("HH", &bytes);
+        ensure_reserved!(reserved);
         Ok(OnionCover {
             cover_size: cover_size,
         })
     }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "H2x", self.cover_size);
+        Ok(())
+    }
+}
+impl WireMessage for OnionCover {
+    const ID: MessageId = MessageId::OnionCover;
 }
 
+#[derive(Debug, PartialEq)]
 pub enum Onion {
     TunnelBuild(OnionTunnelBuild),
-    TunnelReady(OnionTunnelPayload),
-    TunnelIncomming(OnionTunnelID),
-    TunnelDestroy(OnionTunnelID),
-    TunnelData(OnionTunnelPayload),
+    TunnelReady(OnionTunnelReady),
+    TunnelIncomming(OnionTunnelIncomming),
+    TunnelDestroy(OnionTunnelDestroy),
+    TunnelData(OnionTunnelData),
     Cover(OnionCover),
-    Error(OnionError)
+    Error(OnionError),
+    StreamOpen(OnionStreamOpen),
+    StreamClose(OnionStreamClose),
+    StreamData(OnionStreamPayload)
 }
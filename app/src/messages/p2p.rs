@@ -1,4 +1,20 @@
+use errors::*;
+use messages::WireFormat;
+
+use bytes::{Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use rmps::{Deserializer, Serializer};
+use rmps::encode::to_vec;
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/** P2P protocol versions this node speaks **/
+pub const PROTOCOL_VERSIONS: &'static [u16] = &[1];
+
+/** Cells smaller than this can't carry a useful amount of voice data **/
+pub const MIN_CELL_SIZE: u16 = 128;
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct P2PMessage {
@@ -12,6 +28,207 @@ impl P2PMessage {
             data: None
         }
     }
+
+    /** Handshakes and tunnel data carry their payload as is **/
+    pub fn carrying(message_type: P2P, payload: &[u8]) -> P2PMessage {
+        P2PMessage {
+            message_type: message_type,
+            data: Some(payload.to_vec())
+        }
+    }
+
+    /** Opens a connection by advertising what this node is capable of **/
+    pub fn knock(capabilities: &Capabilities) -> Result<P2PMessage> {
+        Ok(P2PMessage {
+            message_type: P2P::Knock,
+            data: Some(to_vec(capabilities)
+                .chain_err(|| ErrorKind::Encode("couldn't serialize capabilities".to_string()))?)
+        })
+    }
+
+    /** Replies to a knock with the outcome of the negotiation **/
+    pub fn whos_there(answer: &Answer) -> Result<P2PMessage> {
+        Ok(P2PMessage {
+            message_type: P2P::WhosThere,
+            data: Some(to_vec(answer)
+                .chain_err(|| ErrorKind::Encode("couldn't serialize knock answer".to_string()))?)
+        })
+    }
+
+    /** Asks the last hop of a tunnel to pass a message on to the next hop **/
+    pub fn forward(relay: &Relay) -> Result<P2PMessage> {
+        Ok(P2PMessage {
+            message_type: P2P::Forward,
+            data: Some(to_vec(relay)
+                .chain_err(|| ErrorKind::Encode("couldn't serialize relay instruction".to_string()))?)
+        })
+    }
+
+    /** Opens a stream on the tunnel - voice streams favour fresh cells over complete ones **/
+    pub fn open_stream(voice: bool) -> Result<P2PMessage> {
+        Ok(P2PMessage {
+            message_type: P2P::StreamOpen,
+            data: Some(to_vec(&voice)
+                .chain_err(|| ErrorKind::Encode("couldn't serialize stream kind".to_string()))?)
+        })
+    }
+
+    /** Lets the far end send more cells - all cells up to the sequence number have been received **/
+    pub fn credit(sequence: u32) -> Result<P2PMessage> {
+        Ok(P2PMessage {
+            message_type: P2P::Credit,
+            data: Some(to_vec(&sequence)
+                .chain_err(|| ErrorKind::Encode("couldn't serialize credit".to_string()))?)
+        })
+    }
+
+    #[allow(or_fun_call)]
+    pub fn payload(&self) -> Result<Bytes> {
+        self.data.as_ref()
+            .map(|data| Bytes::from(&data[..]))
+            .ok_or(ErrorKind::Decode(format!("{} carries no data", self.message_type.name())).into())
+    }
+
+    pub fn capabilities(&self) -> Result<Capabilities> {
+        self.read_data(P2P::Knock)
+    }
+
+    pub fn answer(&self) -> Result<Answer> {
+        self.read_data(P2P::WhosThere)
+    }
+
+    pub fn relay(&self) -> Result<Relay> {
+        self.read_data(P2P::Forward)
+    }
+
+    pub fn voice(&self) -> Result<bool> {
+        self.read_data(P2P::StreamOpen)
+    }
+
+    pub fn credited(&self) -> Result<u32> {
+        self.read_data(P2P::Credit)
+    }
+
+    #[allow(or_fun_call)]
+    fn read_data<T: DeserializeOwned>(&self, expected: P2P) -> Result<T> {
+        if self.message_type != expected {
+            bail!(ErrorKind::Decode(format!("expected {}, but got {}", expected.name(), self.message_type.name())));
+        }
+        let data = self.data.as_ref()
+            .ok_or(ErrorKind::Decode(format!("{} carries no data", expected.name())))?;
+        let mut deserializer = Deserializer::from_slice(data);
+        deserializer.set_max_depth(16);
+        Deserialize::deserialize(&mut deserializer)
+            .chain_err(|| ErrorKind::Decode(format!("malformed {} data", expected.name())))
+    }
+}
+/* MessagePack encoded - carries no API header */
+impl WireFormat for P2PMessage {
+    fn decode(bytes: Bytes) -> Result<P2PMessage> {
+        // Reading from a slice makes sure length prefixes can't claim more bytes than were received
+        let mut deserializer = Deserializer::from_slice(&bytes);
+        deserializer.set_max_depth(16);
+        Deserialize::deserialize(&mut deserializer)
+            .chain_err(|| ErrorKind::Decode("couldn't deserialize P2P message".to_string()))
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        let mut bytes = Vec::new();
+        self.serialize(&mut Serializer::new(&mut bytes))
+            .chain_err(|| ErrorKind::Encode("couldn't serialize P2P message".to_string()))?;
+        buffer.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+/**
+    A P2P message as it travels between two nodes
+    Addressed to the receiving node's tunnel, the sender's tunnel is where replies have to go
+    Knocks are addressed to no tunnel yet - the knocked on node allocates one for them
+**/
+#[derive(Debug, PartialEq)]
+pub struct Datagram {
+    pub receiver_tunnel_id: u32,
+    pub sender_tunnel_id: u32,
+    pub message: P2PMessage
+}
+/* 4B ReceiverTunnelId | 4B SenderTunnelId | Rest P2P message */
+impl WireFormat for Datagram {
+    fn decode(bytes: Bytes) -> Result<Datagram> {
+        ensure_length!(bytes, 8);
+        let (receiver_tunnel_id, sender_tunnel_id) = unpack_structure!("II", &bytes[0..8]);
+        Ok(Datagram {
+            receiver_tunnel_id: receiver_tunnel_id,
+            sender_tunnel_id: sender_tunnel_id,
+            message: P2PMessage::decode(bytes.slice_from(8))?
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        write_structure!(buffer, "II", self.receiver_tunnel_id, self.sender_tunnel_id);
+        self.message.encode(buffer)
+    }
+}
+
+const UNRELIABLE_SEGMENT: u8 = 0;
+const RELIABLE_SEGMENT: u8 = 1;
+const ACK_SEGMENT: u8 = 2;
+
+/** What actually goes into a UDP packet - control messages are acknowledged, tunnel data isn't **/
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    Unreliable(Datagram),
+    /**
+        Retransmitted until the receiver acknowledges its epoch and sequence number
+        The epoch is picked at random by every process, so a restarted peer counting from zero again
+        isn't mistaken for repeating itself
+    **/
+    Reliable(u32, u32, Datagram),
+    Ack(u32, u32)
+}
+/*
+    1B Kind | 3B Reserved | 4B Sequence | 4B Epoch | Rest Datagram
+    Acks carry no datagram, unreliable segments neither a sequence nor an epoch
+*/
+impl WireFormat for Segment {
+    fn decode(bytes: Bytes) -> Result<Segment> {
+        ensure_length!(bytes, 8);
+        let (kind, reserved, reserved_short, sequence) = unpack_structure!("BBHI", &bytes[0..8]);
+        ensure_reserved!(reserved);
+        ensure_reserved!(reserved_short);
+
+        Ok(match kind {
+            UNRELIABLE_SEGMENT => {
+                ensure_reserved!(sequence);
+                Segment::Unreliable(Datagram::decode(bytes.slice_from(8))?)
+            },
+            RELIABLE_SEGMENT => {
+                ensure_length!(bytes, 12);
+                let (epoch,) = unpack_structure!("I", &bytes[8..12]);
+                Segment::Reliable(epoch, sequence, Datagram::decode(bytes.slice_from(12))?)
+            },
+            ACK_SEGMENT => {
+                ensure_length!(exactly bytes, 12);
+                let (epoch,) = unpack_structure!("I", &bytes[8..12]);
+                Segment::Ack(epoch, sequence)
+            },
+            kind => bail!(ErrorKind::Decode(format!("unknown segment kind {}", kind)))
+        })
+    }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        match self {
+            Segment::Unreliable(datagram) => {
+                write_structure!(buffer, "B3xI", UNRELIABLE_SEGMENT, 0);
+                datagram.encode(buffer)
+            },
+            Segment::Reliable(epoch, sequence, datagram) => {
+                write_structure!(buffer, "B3xII", RELIABLE_SEGMENT, sequence, epoch);
+                datagram.encode(buffer)
+            },
+            Segment::Ack(epoch, sequence) => {
+                write_structure!(buffer, "B3xII", ACK_SEGMENT, sequence, epoch);
+                Ok(())
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
@@ -21,5 +238,122 @@ pub enum P2P {
     Handshake,
     Incomming,
     Forward,
-    Data
+    Data,
+    Destroy,
+    StreamOpen,
+    StreamClose,
+    Credit,
+    Keepalive
+}
+impl P2P {
+    /** Human readable message type used for logging **/
+    pub fn name(&self) -> &'static str {
+        match *self {
+            P2P::Knock => "P2PKnock",
+            P2P::WhosThere => "P2PWhosThere",
+            P2P::Handshake => "P2PHandshake",
+            P2P::Incomming => "P2PIncomming",
+            P2P::Forward => "P2PForward",
+            P2P::Data => "P2PData",
+            P2P::Destroy => "P2PDestroy",
+            P2P::StreamOpen => "P2PStreamOpen",
+            P2P::StreamClose => "P2PStreamClose",
+            P2P::Credit => "P2PCredit",
+            P2P::Keepalive => "P2PKeepalive"
+        }
+    }
+}
+
+/** Where the last hop of a tunnel should pass the message on to - tunnels are extended this way **/
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Relay {
+    pub ip_addr: IpAddr,
+    pub port: u16,
+    pub message: P2PMessage
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Padding {
+    None,
+    FixedCell
+}
+impl FromStr for Padding {
+    type Err = Error;
+
+    fn from_str(padding: &str) -> Result<Padding> {
+        Ok(match padding.trim().to_lowercase().as_ref() {
+            "none" => Padding::None,
+            "fixed_cell" => Padding::FixedCell,
+            _ => bail!("padding scheme {} unknown - expected none or fixed_cell", padding)
+        })
+    }
+}
+
+/** Sent along with a knock - padding schemes are listed in order of preference **/
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Capabilities {
+    pub versions: Vec<u16>,
+    pub cell_size: u16,
+    pub cover_traffic: bool,
+    pub padding: Vec<Padding>
+}
+
+/** Settings both ends of a connection agreed upon **/
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Agreement {
+    pub version: u16,
+    pub cell_size: u16,
+    pub cover_traffic: bool,
+    pub padding: Padding
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Answer {
+    Accepted(Agreement),
+    Rejected(String)
+}
+
+impl Capabilities {
+    /**
+        Settles on the highest common version, the smaller cell size and the first padding scheme
+        of the knocking side both ends support - cover traffic is only used if both want it
+    **/
+    #[allow(or_fun_call)]
+    pub fn negotiate(&self, knock: &Capabilities) -> ::std::result::Result<Agreement, String> {
+        let version = knock.versions.iter().filter(|version| self.versions.contains(version)).max()
+            .ok_or(format!("no common protocol version (supported {:?}, offered {:?})",
+                self.versions, knock.versions))?;
+
+        let cell_size = ::std::cmp::min(self.cell_size, knock.cell_size);
+        if cell_size < MIN_CELL_SIZE {
+            return Err(format!("cell size {} is below the minimum of {}", cell_size, MIN_CELL_SIZE));
+        }
+
+        let padding = knock.padding.iter().find(|padding| self.padding.contains(padding))
+            .ok_or(format!("no common padding scheme (supported {:?}, offered {:?})",
+                self.padding, knock.padding))?;
+
+        Ok(Agreement {
+            version: *version,
+            cell_size: cell_size,
+            cover_traffic: self.cover_traffic && knock.cover_traffic,
+            padding: *padding
+        })
+    }
+
+    /** Answer to send back for a received knock **/
+    pub fn answer(&self, knock: &Capabilities) -> Answer {
+        match self.negotiate(knock) {
+            Ok(agreement) => Answer::Accepted(agreement),
+            Err(reason) => Answer::Rejected(reason)
+        }
+    }
+
+    /** Makes sure an agreement sent back by a peer stays within what was offered **/
+    pub fn permits(&self, agreement: &Agreement) -> bool {
+        self.versions.contains(&agreement.version) &&
+            agreement.cell_size <= self.cell_size && agreement.cell_size >= MIN_CELL_SIZE &&
+            self.padding.contains(&agreement.padding) &&
+            (self.cover_traffic || !agreement.cover_traffic)
+    }
 }
@@ -1,42 +1,67 @@
 use errors::*;
 
 use bit_field::BitField;
+use bytes::{Bytes, BytesMut};
 
-use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
+use messages::{MessageId, WireFormat, WireMessage};
+use messages::utilities::{decode_ip_addr, encode_ip_addr, validate_hostkey};
 
+use std::net::IpAddr;
+
+#[derive(Debug, PartialEq)]
 pub struct RpsQuery {}
-impl RpsQuery {
-    pub fn encode(self) -> Result<Vec<u8>> {
-        Ok(vec![])
+impl WireFormat for RpsQuery {
+    fn decode(bytes: Bytes) -> Result<RpsQuery> {
+        ensure_length!(exactly bytes, 0);
+        Ok(RpsQuery {})
+    }
+    fn encode(self, _buffer: &mut BytesMut) -> Result<()> {
+        Ok(())
     }
 }
+impl WireMessage for RpsQuery {
+    const ID: MessageId = MessageId::RpsQuery;
+}
 
+#[derive(Debug, PartialEq)]
 pub struct RpsPeer {
     pub port: u16,
     pub ip_addr: IpAddr,
-    pub hostkey: Vec<u8>
+    pub hostkey: Bytes
 }
 /* 2B Port | 1B Reserved | 7b1b IPv | Rest Hostkey */
-impl RpsPeer {
-    pub fn decode(bytes: Vec<u8>) -> Result<RpsPeer> {
-        let (port, ipv) = unpack_structure!("HxB", &bytes);
-
-        let (next_field_offset, ip_addr) = if ipv.get_bit(0) {
-            let (i0, i1, i2, i3, i4, i5, i6, i7) = unpack_structure!("8H", &bytes[4..18]);
-            (18, IpAddr::V6(Ipv6Addr::new(i0, i1, i2, i3, i4, i5, i6, i7)))
-        } else {
-            let (i0, i1, i2, i3) = unpack_structure!("4B", &bytes[4..8]);
-            (8, IpAddr::V4(Ipv4Addr::new(i0, i1, i2, i3)))
-        };
+impl WireFormat for RpsPeer {
+    fn decode(bytes: Bytes) -> Result<RpsPeer> {
+        ensure_length!(bytes, 4);
+        let (port, reserved, ipv) = unpack_structure!("HBB", &bytes[0..4]);
+        ensure_reserved!(reserved);
+        ensure_reserved!(ipv & !0b1);
+
+        let (ip_addr, ip_length) = decode_ip_addr(ipv.get_bit(0), &bytes[4..])?;
+        let next_field_offset = 4 + ip_length;
+        validate_hostkey(&bytes[next_field_offset..])?;
 
         Ok(RpsPeer {
             port: port,
             ip_addr: ip_addr,
-            hostkey: bytes[next_field_offset..].to_vec()
+            hostkey: bytes.slice_from(next_field_offset)
         })
     }
+    fn encode(self, buffer: &mut BytesMut) -> Result<()> {
+        validate_hostkey(&self.hostkey)
+            .chain_err(|| ErrorKind::Encode("hostkey is not a well-formed DER key".to_string()))?;
+        let (ipv6, ip_addr) = encode_ip_addr(&self.ip_addr);
+        write_structure!(buffer, "HBB", self.port, 0, boolean!(ipv6));
+        buffer.extend_from_slice(&ip_addr);
+        buffer.extend_from_slice(&self.hostkey);
+        Ok(())
+    }
+}
+impl WireMessage for RpsPeer {
+    const ID: MessageId = MessageId::RpsPeer;
 }
 
+#[derive(Debug, PartialEq)]
 pub enum Rps {
     Query(RpsQuery),
     Peer(RpsPeer)
@@ -0,0 +1,570 @@
+use errors::*;
+use messages::{Message, MessageId, WireFormat, decode_message, encode_message};
+
+use bytes::{Bytes, BytesMut};
+use messages::auth::*;
+use messages::onion::*;
+use messages::rps::*;
+use messages::cell::{Cell, DigestKey};
+use messages::p2p::{P2P, P2PMessage, Datagram, Segment, Relay, Capabilities, Agreement, Answer, Padding};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/** Minimal xorshift generator - good enough to produce garbage input **/
+struct Garbage(u32);
+impl Garbage {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn bytes(&mut self, length: usize) -> Vec<u8> {
+        (0..length).map(|_| self.next() as u8).collect()
+    }
+}
+
+fn frame(message_id: MessageId, body: &[u8]) -> Vec<u8> {
+    let mut bytes = structure!("2H").pack(body.len() as u16 + 4, message_id as u16).unwrap();
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/** Well-formed messages along with the minimal length their body may be truncated to **/
+fn valid_messages() -> Vec<(Vec<u8>, usize)> {
+    let hostkey = [0x30, 0x03, 0x02, 0x01, 0x01];
+    let ipv4_peer = [&[0x1f, 0x40, 0x00, 0x00, 127, 0, 0, 1][..], &hostkey[..]].concat();
+    let ipv6_peer = [&[0x1f, 0x40, 0x00, 0x01][..], &[0; 15][..], &[1][..], &hostkey[..]].concat();
+
+    vec![
+        (frame(MessageId::OnionTunnelBuild, &[&[0x00, 0x00, 0x1f, 0x40, 10, 0, 0, 1][..], &hostkey[..]].concat()), 13),
+        (frame(MessageId::OnionTunnelReady, &[0, 0, 0, 1, 0xde, 0xad]), 4),
+        (frame(MessageId::OnionTunnelData, &[0, 0, 0, 1, 0xbe, 0xef]), 4),
+        (frame(MessageId::OnionTunnelIncomming, &[0, 0, 0, 1]), 4),
+        (frame(MessageId::OnionTunnelDestroy, &[0, 0, 0, 1]), 4),
+        (frame(MessageId::OnionCover, &[0, 16, 0, 0]), 4),
+        (frame(MessageId::OnionStreamOpen, &[0, 0, 0, 1, 0, 2, 0, 0]), 8),
+        (frame(MessageId::OnionStreamData, &[0, 0, 0, 1, 0, 2, 0, 0, 0xbe, 0xef]), 8),
+        (frame(MessageId::AuthSessionHS1, &[0, 0, 0, 1, 0, 0, 0, 2, 0xaa]), 8),
+        (frame(MessageId::AuthSessionHS2, &[0, 0, 0, 1, 0, 0, 0, 2, 0xaa]), 8),
+        (frame(MessageId::AuthSessionIncommingHS2, &[0, 0, 0, 1, 0, 0, 0, 2, 0xaa]), 8),
+        (frame(MessageId::AuthCipherEncryptResp, &[0, 0, 0, 1, 0, 0, 0, 2, 0xaa]), 8),
+        (frame(MessageId::AuthCipherDecryptResp, &[0, 0, 0, 0, 0, 0, 0, 2, 0xaa]), 8),
+        (frame(MessageId::AuthSessionError, &[0, 0, 0, 0, 0, 0, 0, 2]), 8),
+        (frame(MessageId::RpsPeer, &ipv4_peer), 13),
+        (frame(MessageId::RpsPeer, &ipv6_peer), 25)
+    ]
+}
+
+/** One instance of every message variant, filled with values that exercise every field **/
+fn every_message() -> Vec<Message> {
+    let hostkey = Bytes::from_static(&[0x30, 0x03, 0x02, 0x01, 0x01]);
+    let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+    let ipv6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0xdead, 0xbeef));
+
+    vec![
+        Message::Onion(Onion::TunnelBuild(OnionTunnelBuild { onion_tunnel: 8000, ip_addr: ipv4, hostkey: hostkey.clone() })),
+        Message::Onion(Onion::TunnelBuild(OnionTunnelBuild { onion_tunnel: 8001, ip_addr: ipv6, hostkey: hostkey.clone() })),
+        Message::Onion(Onion::TunnelReady(OnionTunnelReady(
+            OnionTunnelPayload { tunnel_id: 0xfedc_ba98, payload: hostkey.clone() }))),
+        Message::Onion(Onion::TunnelIncomming(OnionTunnelIncomming(OnionTunnelID { tunnel_id: 7 }))),
+        Message::Onion(Onion::TunnelDestroy(OnionTunnelDestroy(OnionTunnelID { tunnel_id: 0x0102_0304 }))),
+        Message::Onion(Onion::TunnelData(OnionTunnelData(
+            OnionTunnelPayload { tunnel_id: 9, payload: Bytes::from(vec![]) }))),
+        Message::Onion(Onion::TunnelData(OnionTunnelData(
+            OnionTunnelPayload { tunnel_id: 9, payload: Bytes::from(vec![0xab; 512]) }))),
+        Message::Onion(Onion::Cover(OnionCover { cover_size: 0x1234 })),
+        Message::Onion(Onion::Error(OnionError { tunnel_id: 3, request_type: MessageId::OnionTunnelBuild as u16 })),
+        Message::Onion(Onion::StreamOpen(OnionStreamOpen(OnionStream { tunnel_id: 4, stream_id: 1, voice: true }))),
+        Message::Onion(Onion::StreamClose(OnionStreamClose(
+            OnionStream { tunnel_id: 4, stream_id: 0xffff, voice: false }))),
+        Message::Onion(Onion::StreamData(OnionStreamPayload { tunnel_id: 4, stream_id: 2, payload: Bytes::from(vec![5; 64]) })),
+
+        Message::Auth(Auth::SessionStart(AuthSessionStart { request_id: 1, hostkey: hostkey.clone() })),
+        Message::Auth(Auth::SessionHS1(AuthSessionHS1(
+            AuthSessionHS { session_id: 2, request_id: 3, payload: Bytes::from(vec![1, 2, 3]) }))),
+        Message::Auth(Auth::SessionIncommingHS1(AuthSessionHS1Response { request_id: 4, payload: Bytes::from(vec![4, 5]) })),
+        Message::Auth(Auth::SessionHS2(AuthSessionHS2(
+            AuthSessionHS { session_id: 5, request_id: 6, payload: Bytes::from(vec![6]) }))),
+        Message::Auth(Auth::SessionIncommingHS2(AuthSessionIncommingHS2(
+            AuthSessionHS { session_id: 7, request_id: 8, payload: Bytes::from(vec![]) }))),
+        Message::Auth(Auth::CipherEncrypt(AuthCipherEncrypt(
+            AuthCipherCrypt { session_id: 9, request_id: 10, cleartext: true, payload: Bytes::from(vec![7, 8]) }))),
+        Message::Auth(Auth::CipherEncryptResp(AuthCipherEncryptResp(
+            AuthCipherCryptResp { request_id: 11, cleartext: false, payload: Bytes::from(vec![9]) }))),
+        Message::Auth(Auth::CipherDecrypt(AuthCipherDecrypt(
+            AuthCipherCrypt { session_id: 12, request_id: 13, cleartext: false, payload: Bytes::from(vec![]) }))),
+        Message::Auth(Auth::CipherDecryptResp(AuthCipherDecryptResp(
+            AuthCipherCryptResp { request_id: 14, cleartext: true, payload: Bytes::from(vec![10, 11]) }))),
+        Message::Auth(Auth::SessionClose(AuthSessionClose { session_id: 0xffff })),
+        Message::Auth(Auth::SessionError(AuthSessionError { request_id: 0xffff_ffff })),
+
+        Message::Rps(Rps::Query(RpsQuery {})),
+        Message::Rps(Rps::Peer(RpsPeer { port: 6001, ip_addr: ipv4, hostkey: hostkey.clone() })),
+        Message::Rps(Rps::Peer(RpsPeer { port: 6002, ip_addr: ipv6, hostkey: hostkey }))
+    ]
+}
+
+fn decode(bytes: &[u8]) -> Result<Message> {
+    decode_message(Bytes::from(bytes))
+}
+
+fn is_decode_error(result: Result<::messages::Message>) -> bool {
+    match result {
+        Err(Error(ErrorKind::Decode(_), _)) => true,
+        _ => false
+    }
+}
+
+#[test]
+fn valid_messages_decode() {
+    for (message, _) in valid_messages() {
+        assert!(decode(&message).is_ok());
+    }
+}
+
+#[test]
+fn truncated_messages_are_rejected() {
+    for (message, _) in valid_messages() {
+        for length in 0..message.len() {
+            assert!(decode(&message[..length]).is_err());
+        }
+    }
+}
+
+#[test]
+fn truncated_bodies_with_matching_header_are_rejected() {
+    for (message, minimal_length) in valid_messages() {
+        let (_, message_id): (u16, u16) = structure!("2H").unpack(&message[0..4]).unwrap();
+
+        for length in 0..minimal_length {
+            let mut reframed = structure!("2H").pack(length as u16 + 4, message_id).unwrap();
+            reframed.extend_from_slice(&message[4..4 + length]);
+            assert!(is_decode_error(decode(&reframed)));
+        }
+    }
+}
+
+#[test]
+fn reserved_bits_are_rejected() {
+    let mut build = valid_messages().remove(0).0;
+    build[4] = 0x80;
+    assert!(is_decode_error(decode(&build)));
+
+    let mut build = valid_messages().remove(0).0;
+    build[5] = 0x02;
+    assert!(is_decode_error(decode(&build)));
+
+    let mut cover = valid_messages().remove(5).0;
+    cover[7] = 0x01;
+    assert!(is_decode_error(decode(&cover)));
+
+    for &(index, bit) in &[(10, 0x01), (11, 0x02)] {
+        let mut stream = valid_messages().remove(6).0;
+        stream[index] = bit;
+        assert!(is_decode_error(decode(&stream)));
+    }
+}
+
+#[test]
+fn header_length_below_header_size_is_rejected() {
+    for length in 0..4 {
+        let bytes = structure!("2H").pack(length, MessageId::OnionTunnelData as u16).unwrap();
+        assert!(is_decode_error(decode(&bytes)));
+    }
+}
+
+#[test]
+fn random_buffers_do_not_panic() {
+    let mut garbage = Garbage(0x2545_f491);
+    for _ in 0..20000 {
+        let length = (garbage.next() % 64) as usize;
+        let _ = decode(&garbage.bytes(length));
+    }
+}
+
+#[test]
+fn random_bodies_with_valid_header_do_not_panic() {
+    let mut garbage = Garbage(0x9e37_79b9);
+    let message_ids = MessageId::all();
+
+    for _ in 0..20000 {
+        let message_id = message_ids[garbage.next() as usize % message_ids.len()] as u16;
+        let length = (garbage.next() % 48) as usize;
+        let mut bytes = structure!("2H").pack(length as u16 + 4, message_id).unwrap();
+        bytes.extend(garbage.bytes(length));
+
+        let _ = decode(&bytes);
+    }
+}
+
+/** Encodes the struct and expects to decode the very same one **/
+macro_rules! assert_round_trip {
+    ($structure:ident $fields:tt) => {
+        let mut buffer = BytesMut::with_capacity(64);
+        $structure $fields.encode(&mut buffer).unwrap();
+        assert_eq!($structure::decode(buffer.freeze()).unwrap(), $structure $fields);
+    }
+}
+
+#[test]
+fn every_struct_round_trips() {
+    let hostkey = Bytes::from_static(&[0x30, 0x03, 0x02, 0x01, 0x01]);
+    let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+    let ipv6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0xdead, 0xbeef));
+
+    assert_round_trip!(OnionTunnelBuild { onion_tunnel: 8000, ip_addr: ipv4, hostkey: hostkey.clone() });
+    assert_round_trip!(OnionTunnelBuild { onion_tunnel: 8001, ip_addr: ipv6, hostkey: hostkey.clone() });
+    assert_round_trip!(OnionTunnelPayload { tunnel_id: 0xfedc_ba98, payload: hostkey.clone() });
+    assert_round_trip!(OnionTunnelPayload { tunnel_id: 9, payload: Bytes::from(vec![0xab; 512]) });
+    assert_round_trip!(OnionTunnelID { tunnel_id: 0x0102_0304 });
+    assert_round_trip!(OnionCover { cover_size: 0x1234 });
+    assert_round_trip!(OnionError { tunnel_id: 3, request_type: MessageId::OnionTunnelBuild as u16 });
+
+    assert_round_trip!(AuthSessionStart { request_id: 1, hostkey: hostkey.clone() });
+    assert_round_trip!(AuthSessionHS { session_id: 2, request_id: 3, payload: Bytes::from(vec![1, 2, 3]) });
+    assert_round_trip!(AuthSessionHS1Response { request_id: 4, payload: Bytes::from(vec![4, 5]) });
+    assert_round_trip!(AuthCipherCrypt { session_id: 9, request_id: 10, cleartext: true, payload: Bytes::from(vec![7, 8]) });
+    assert_round_trip!(AuthCipherCrypt { session_id: 12, request_id: 13, cleartext: false, payload: Bytes::from(vec![]) });
+    assert_round_trip!(AuthCipherCryptResp { request_id: 11, cleartext: false, payload: Bytes::from(vec![9]) });
+    assert_round_trip!(AuthSessionClose { session_id: 0xffff });
+    assert_round_trip!(AuthSessionError { request_id: 0xffff_ffff });
+
+    assert_round_trip!(RpsQuery {});
+    assert_round_trip!(RpsPeer { port: 6001, ip_addr: ipv4, hostkey: hostkey.clone() });
+    assert_round_trip!(RpsPeer { port: 6002, ip_addr: ipv6, hostkey: hostkey.clone() });
+}
+
+#[test]
+fn every_message_round_trips() {
+    // Messages can't be cloned, so the expected values come from a second identical set
+    for (message, expected) in every_message().into_iter().zip(every_message()) {
+        let bytes = encode_message(message).unwrap();
+        assert_eq!(decode(&bytes).unwrap(), expected);
+    }
+}
+
+#[test]
+fn every_message_id_is_covered() {
+    let mut covered = vec![];
+    for message in every_message() {
+        let message_id = message.id();
+        assert_eq!(message.name(), format!("{:?}", message_id));
+
+        let bytes = encode_message(message).unwrap();
+        let (_, encoded_id): (u16, u16) = structure!("2H").unpack(&bytes[0..4]).unwrap();
+        assert_eq!(encoded_id, message_id as u16);
+        covered.push(message_id);
+    }
+
+    assert_eq!(MessageId::all().len(), 23);
+    for message_id in MessageId::all() {
+        assert!(covered.contains(message_id), "{:?} has no sample message", message_id);
+    }
+}
+
+#[test]
+fn valid_messages_encode_to_the_same_bytes() {
+    for (message, _) in valid_messages() {
+        assert_eq!(encode_message(decode(&message).unwrap()).unwrap(), message);
+    }
+}
+
+#[test]
+fn encoded_header_carries_length_and_type() {
+    let bytes = encode_message(Message::Onion(Onion::TunnelDestroy(OnionTunnelDestroy(
+        OnionTunnelID { tunnel_id: 1 })))).unwrap();
+    assert_eq!(bytes, vec![0, 8, 2, 51, 0, 0, 0, 1]);
+}
+
+fn rps_peer_with_hostkey(ip_addr: IpAddr, hostkey: Vec<u8>) -> Message {
+    Message::Rps(Rps::Peer(RpsPeer { port: 6001, ip_addr: ip_addr, hostkey: Bytes::from(hostkey) }))
+}
+
+fn rps_peer(ip_addr: IpAddr) -> Message {
+    rps_peer_with_hostkey(ip_addr, vec![0x30, 0x03, 0x02, 0x01, 0x01])
+}
+
+#[test]
+fn ipv4_addresses_are_encoded_without_the_ipv6_flag() {
+    let bytes = encode_message(rps_peer(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))).unwrap();
+    assert_eq!(&bytes[4..12], &[0x17, 0x71, 0, 0, 10, 0, 0, 1]);
+    assert_eq!(bytes.len(), 4 + 4 + 4 + 5);
+
+    let build = OnionTunnelBuild { onion_tunnel: 1, ip_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        hostkey: Bytes::from_static(&[0x30, 0]) };
+    let mut buffer = BytesMut::with_capacity(0);
+    build.encode(&mut buffer).unwrap();
+    assert_eq!(buffer, vec![0, 0, 0, 1, 10, 0, 0, 1, 0x30, 0]);
+}
+
+#[test]
+fn ipv6_addresses_are_encoded_with_the_ipv6_flag() {
+    let ip_addr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+    let bytes = encode_message(rps_peer(ip_addr)).unwrap();
+    assert_eq!(&bytes[4..8], &[0x17, 0x71, 0, 1]);
+    assert_eq!(&bytes[8..24], &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(bytes.len(), 4 + 4 + 16 + 5);
+
+    let build = OnionTunnelBuild { onion_tunnel: 1, ip_addr: ip_addr, hostkey: Bytes::from_static(&[0x30, 0]) };
+    let mut buffer = BytesMut::with_capacity(0);
+    build.encode(&mut buffer).unwrap();
+    assert_eq!(&buffer[0..4], &[0, 1, 0, 1]);
+    assert_eq!(OnionTunnelBuild::decode(buffer.freeze()).unwrap().ip_addr, ip_addr);
+}
+
+#[test]
+fn ipv4_mapped_addresses_are_canonical() {
+    let mapped = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+    let bytes = encode_message(rps_peer(mapped)).unwrap();
+    assert_eq!(bytes, encode_message(rps_peer(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))).unwrap());
+
+    // Peers sending the mapped form with the IPv6 flag are understood as IPv4 as well
+    let body = [&[0x17, 0x71, 0, 1][..], &[0; 10][..], &[0xff, 0xff, 10, 0, 0, 1][..], &[0x30, 0][..]].concat();
+    assert_eq!(decode(&frame(MessageId::RpsPeer, &body)).unwrap(),
+        rps_peer_with_hostkey(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), vec![0x30, 0]));
+
+    // Only mapped addresses are collapsed - loopback stays IPv6
+    let loopback = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+    let bytes = encode_message(rps_peer(loopback)).unwrap();
+    assert_eq!(decode(&bytes).unwrap(), rps_peer(loopback));
+}
+
+#[test]
+fn mismatched_ip_version_flag_is_rejected() {
+    let ipv4_body = [&[0x17, 0x71, 0, 0, 10, 0, 0, 1][..], &[0x30, 0x03, 0x02, 0x01, 0x01][..]].concat();
+    let mut flagged_ipv6 = ipv4_body.clone();
+    flagged_ipv6[3] = 1;
+    assert!(is_decode_error(decode(&frame(MessageId::RpsPeer, &flagged_ipv6))));
+
+    let ipv6_body = [&[0x17, 0x71, 0, 1][..], &[0x20, 0x01, 0x0d, 0xb8][..], &[0; 12][..],
+        &[0x30, 0x03, 0x02, 0x01, 0x01][..]].concat();
+    let mut flagged_ipv4 = ipv6_body.clone();
+    flagged_ipv4[3] = 0;
+    assert!(is_decode_error(decode(&frame(MessageId::RpsPeer, &flagged_ipv4))));
+}
+
+#[test]
+fn malformed_hostkeys_are_rejected() {
+    let hostkeys: Vec<Vec<u8>> = vec![
+        vec![0x02, 0x01, 0x01],                     // Not a sequence
+        vec![0x30, 0x04, 0x02, 0x01, 0x01],         // Length exceeds the remaining bytes
+        vec![0x30, 0x03, 0x02, 0x01, 0x01, 0x00],   // Trailing bytes
+        vec![0x30, 0x03, 0x02, 0x02, 0x01],         // Inner element exceeds its sequence
+        vec![0x30, 0x81, 0x03, 0x02, 0x01, 0x01],   // Length not minimally encoded
+        vec![0x30, 0x80, 0x02, 0x01, 0x01, 0, 0],   // Indefinite length is not DER
+        vec![0x3f, 0x01, 0x00]                      // High tag number
+    ];
+
+    for hostkey in hostkeys {
+        let body = [&[0x17, 0x71, 0, 0, 10, 0, 0, 1][..], &hostkey[..]].concat();
+        assert!(is_decode_error(decode(&frame(MessageId::RpsPeer, &body))));
+        assert!(encode_message(rps_peer_with_hostkey(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), hostkey)).is_err());
+    }
+}
+
+#[test]
+fn long_form_hostkeys_are_accepted() {
+    let mut hostkey = vec![0x30, 0x82, 0x01, 0x04, 0x04, 0x82, 0x01, 0x00];
+    hostkey.extend(vec![0xaa; 256]);
+    let message = rps_peer_with_hostkey(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), hostkey.clone());
+    let bytes = encode_message(message).unwrap();
+    assert_eq!(decode(&bytes).unwrap(), rps_peer_with_hostkey(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), hostkey));
+}
+
+fn capabilities(versions: Vec<u16>, cell_size: u16, cover_traffic: bool, padding: Vec<Padding>) -> Capabilities {
+    Capabilities { versions: versions, cell_size: cell_size, cover_traffic: cover_traffic, padding: padding }
+}
+
+#[test]
+fn negotiation_settles_on_the_highest_common_settings() {
+    let ours = capabilities(vec![1, 2, 3], 512, true, vec![Padding::FixedCell, Padding::None]);
+    let knock = capabilities(vec![2, 3, 4], 1024, false, vec![Padding::None, Padding::FixedCell]);
+
+    assert_eq!(ours.answer(&knock), Answer::Accepted(Agreement {
+        version: 3,
+        cell_size: 512,
+        cover_traffic: false,
+        padding: Padding::None
+    }));
+}
+
+#[test]
+fn negotiation_rejects_incompatible_peers() {
+    let ours = capabilities(vec![1], 512, false, vec![Padding::FixedCell]);
+
+    let knocks = vec![
+        capabilities(vec![2, 3], 512, false, vec![Padding::FixedCell]),
+        capabilities(vec![1], 64, false, vec![Padding::FixedCell]),
+        capabilities(vec![1], 512, false, vec![Padding::None])
+    ];
+    for knock in knocks {
+        match ours.answer(&knock) {
+            Answer::Rejected(_) => {},
+            answer => panic!("expected a rejection, but got {:?}", answer)
+        }
+    }
+}
+
+#[test]
+fn agreements_outside_the_offer_are_not_permitted() {
+    let ours = capabilities(vec![1, 2], 512, false, vec![Padding::FixedCell]);
+    let agreement = Agreement { version: 2, cell_size: 512, cover_traffic: false, padding: Padding::FixedCell };
+    assert!(ours.permits(&agreement));
+
+    assert!(!ours.permits(&Agreement { version: 3, ..agreement.clone() }));
+    assert!(!ours.permits(&Agreement { cell_size: 1024, ..agreement.clone() }));
+    assert!(!ours.permits(&Agreement { cover_traffic: true, ..agreement.clone() }));
+    assert!(!ours.permits(&Agreement { padding: Padding::None, ..agreement }));
+}
+
+#[test]
+fn knock_and_answer_survive_the_wire() {
+    let ours = capabilities(vec![1], 512, true, vec![Padding::FixedCell, Padding::None]);
+    let mut bytes = BytesMut::with_capacity(64);
+    P2PMessage::knock(&ours).unwrap().encode(&mut bytes).unwrap();
+    let message = P2PMessage::decode(bytes.freeze()).unwrap();
+    assert_eq!(message.capabilities().unwrap(), ours);
+
+    let answer = ours.answer(&ours);
+    let mut bytes = BytesMut::with_capacity(64);
+    P2PMessage::whos_there(&answer).unwrap().encode(&mut bytes).unwrap();
+    let message = P2PMessage::decode(bytes.freeze()).unwrap();
+    assert!(message.capabilities().is_err());
+    assert_eq!(message.answer().unwrap(), answer);
+
+    assert!(P2PMessage::new(P2P::Knock).capabilities().is_err());
+}
+
+#[test]
+fn datagrams_carry_both_tunnel_ids() {
+    let datagram = Datagram {
+        receiver_tunnel_id: 0x01020304,
+        sender_tunnel_id: 7,
+        message: P2PMessage { message_type: P2P::Data, data: Some(vec![1, 2, 3, 4]) }
+    };
+    let mut buffer = BytesMut::with_capacity(0);
+    datagram.encode(&mut buffer).unwrap();
+    assert_eq!(&buffer[0..8], &[1, 2, 3, 4, 0, 0, 0, 7]);
+
+    let decoded = Datagram::decode(buffer.clone().freeze()).unwrap();
+    assert_eq!(decoded.receiver_tunnel_id, 0x01020304);
+    assert_eq!(decoded.sender_tunnel_id, 7);
+    assert_eq!(decoded.message, P2PMessage { message_type: P2P::Data, data: Some(vec![1, 2, 3, 4]) });
+
+    for length in 0..buffer.len() {
+        assert!(Datagram::decode(buffer.clone().freeze().slice_to(length)).is_err());
+    }
+}
+
+#[test]
+fn segments_round_trip_and_reject_malformed_headers() {
+    let datagram = || Datagram {
+        receiver_tunnel_id: 1,
+        sender_tunnel_id: 2,
+        message: P2PMessage::new(P2P::Destroy)
+    };
+
+    let segments = || vec![Segment::Unreliable(datagram()), Segment::Reliable(7, 42, datagram()), Segment::Ack(7, 42)];
+    for (segment, expected) in segments().into_iter().zip(segments()) {
+        let mut buffer = BytesMut::with_capacity(0);
+        segment.encode(&mut buffer).unwrap();
+        assert_eq!(Segment::decode(buffer.freeze()).unwrap(), expected);
+    }
+
+    // Unknown kind, reserved bits, sequenced unreliable segment
+    for header in vec![[3, 0, 0, 0, 0, 0, 0, 0], [1, 1, 0, 0, 0, 0, 0, 0], [0, 0, 0, 0, 0, 0, 0, 1]] {
+        let mut buffer = BytesMut::from(&header[..]);
+        datagram().encode(&mut buffer).unwrap();
+        assert!(Segment::decode(buffer.freeze()).is_err());
+    }
+    assert!(Segment::decode(Bytes::from_static(&[2, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 7, 0])).is_err());
+    // Reliable segments and acks without an epoch
+    assert!(Segment::decode(Bytes::from_static(&[1, 0, 0, 0, 0, 0, 0, 1, 0, 0])).is_err());
+    assert!(Segment::decode(Bytes::from_static(&[2, 0, 0, 0, 0, 0, 0, 1])).is_err());
+}
+
+fn cell(recognised: bool, stream_id: u16) -> Cell {
+    Cell { recognised: recognised, stream_id: stream_id, sequence: 9, payload: Bytes::from_static(b"voice") }
+}
+
+fn key() -> DigestKey {
+    DigestKey::decode(Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16])).unwrap()
+}
+
+#[test]
+fn digest_keys_round_trip() {
+    let mut buffer = BytesMut::with_capacity(16);
+    key().encode(&mut buffer).unwrap();
+    assert_eq!(&buffer[..], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+    assert!(DigestKey::decode(buffer.freeze().slice_to(15)).is_err());
+
+    assert_ne!(DigestKey::random(), DigestKey::random());
+}
+
+fn encoded_cell(recognised: bool, stream_id: u16) -> BytesMut {
+    let mut buffer = BytesMut::with_capacity(0);
+    cell(recognised, stream_id).encode(&key(), &mut buffer).unwrap();
+    buffer
+}
+
+#[test]
+fn cells_round_trip_with_their_digest() {
+    for &(recognised, stream_id) in &[(true, 0), (true, 0xbeef), (false, 0)] {
+        let decoded = Cell::decode(encoded_cell(recognised, stream_id).freeze(), &key()).unwrap();
+        assert_eq!(decoded, cell(recognised, stream_id));
+    }
+}
+
+#[test]
+fn relayed_cells_belong_to_no_stream() {
+    match Cell::decode(encoded_cell(false, 3).freeze(), &key()) {
+        Err(Error(ErrorKind::Tampered(_), _)) => (),
+        result => panic!("relayed layer with a stream went unnoticed: {:?}", result)
+    }
+}
+
+#[test]
+fn tampered_cells_are_detected() {
+    let cell = encoded_cell(true, 7);
+    for index in 0..cell.len() {
+        for bit in 0..8 {
+            let mut tampered = cell.clone();
+            tampered[index] ^= 1 << bit;
+            match Cell::decode(tampered.freeze(), &key()) {
+                Err(Error(ErrorKind::Tampered(_), _)) => (),
+                result => panic!("flipping bit {} of byte {} went unnoticed: {:?}", bit, index, result)
+            }
+        }
+    }
+    assert!(Cell::decode(cell.freeze().slice_to(11), &key()).is_err());
+}
+
+#[test]
+fn cells_only_pass_their_digest_within_their_session() {
+    for other in &[DigestKey::random(), DigestKey::random()] {
+        match Cell::decode(encoded_cell(true, 7).freeze(), other) {
+            Err(Error(ErrorKind::Tampered(_), _)) => (),
+            result => panic!("layer of another session went unnoticed: {:?}", result)
+        }
+    }
+}
+
+#[test]
+fn relay_instructions_carry_the_wrapped_message() {
+    let relay = Relay {
+        ip_addr: IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        port: 4567,
+        message: P2PMessage::carrying(P2P::Handshake, &[1, 2, 3])
+    };
+
+    let mut bytes = BytesMut::with_capacity(64);
+    P2PMessage::forward(&relay).unwrap().encode(&mut bytes).unwrap();
+    let message = P2PMessage::decode(bytes.freeze()).unwrap();
+    assert_eq!(message.relay().unwrap(), relay);
+    assert_eq!(message.relay().unwrap().message.payload().unwrap(), Bytes::from(&[1u8, 2, 3][..]));
+
+    assert!(P2PMessage::new(P2P::Forward).relay().is_err());
+    assert!(P2PMessage::new(P2P::Data).payload().is_err());
+}
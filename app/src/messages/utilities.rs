@@ -1,17 +1,181 @@
+use errors::*;
+
+use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
+
 macro_rules! unpack_structure {
     ($format:expr, $source:expr) => {
-        structure!($format).unpack($source).chain_err(|| "failed to unpack defined structure")?
+        structure!($format).unpack($source)
+            .chain_err(|| ErrorKind::Decode("failed to unpack defined structure".to_string()))?
     }
 }
 
 macro_rules! pack_structure {
     ($format:expr, $($input:expr),*) => {
-        structure!($format).pack($($input),*).chain_err(|| "failed to pack defined structure")?
+        structure!($format).pack($($input),*)
+            .chain_err(|| ErrorKind::Encode("failed to pack defined structure".to_string()))?
+    }
+}
+
+/** Packs a structure straight onto the end of an outgoing buffer **/
+macro_rules! write_structure {
+    ($buffer:expr, $format:expr, $($input:expr),*) => {
+        $buffer.extend_from_slice(&pack_structure!($format, $($input),*))
     }
 }
 
+/**
+    Declares messages which share a layout but carry ids of their own as newtypes around it
+    The newtypes dereference to the layout, so its fields can be read straight off them
+**/
+macro_rules! shared_layout {
+    ($layout:ident: $($message:ident),*) => {$(
+        #[derive(Debug, PartialEq)]
+        pub struct $message(pub $layout);
+        impl ::messages::WireFormat for $message {
+            fn decode(bytes: ::bytes::Bytes) -> Result<$message> {
+                Ok($message(<$layout as ::messages::WireFormat>::decode(bytes)?))
+            }
+            fn encode(self, buffer: &mut ::bytes::BytesMut) -> Result<()> {
+                self.0.encode(buffer)
+            }
+        }
+        impl ::messages::WireMessage for $message {
+            const ID: ::messages::MessageId = ::messages::MessageId::$message;
+        }
+        impl ::std::ops::Deref for $message {
+            type Target = $layout;
+            fn deref(&self) -> &$layout {
+                &self.0
+            }
+        }
+    )*}
+}
+
 macro_rules! boolean {
     ($set:expr) => {
         if $set { 0b1 } else { 0b0 }
     }
 }
+
+/** Bails out with a decode error if less than the required amount of bytes is available **/
+macro_rules! ensure_length {
+    ($bytes:expr, $length:expr) => {
+        if $bytes.len() < $length {
+            bail!(ErrorKind::Decode(format!("expected at least {} bytes, but got {}", $length, $bytes.len())));
+        }
+    };
+    (exactly $bytes:expr, $length:expr) => {
+        if $bytes.len() != $length {
+            bail!(ErrorKind::Decode(format!("expected exactly {} bytes, but got {}", $length, $bytes.len())));
+        }
+    }
+}
+
+/** Bails out with a decode error if any reserved bit is set **/
+macro_rules! ensure_reserved {
+    ($value:expr) => {
+        if $value != 0 {
+            bail!(ErrorKind::Decode(format!("reserved bits are set ({:#x})", $value)));
+        }
+    }
+}
+
+/**
+    Reads an IPv4 or IPv6 address from the start of `bytes` depending on the IP version flag
+    IPv4-mapped IPv6 addresses are returned as plain IPv4 so both encodings compare equal
+    Returns the address and the amount of bytes it occupied
+**/
+pub fn decode_ip_addr(ipv6: bool, bytes: &[u8]) -> Result<(IpAddr, usize)> {
+    Ok(if ipv6 {
+        ensure_length!(bytes, 16);
+        let (i0, i1, i2, i3, i4, i5, i6, i7) = unpack_structure!("8H", &bytes[0..16]);
+        (canonical_ip_addr(IpAddr::V6(Ipv6Addr::new(i0, i1, i2, i3, i4, i5, i6, i7))), 16)
+    } else {
+        ensure_length!(bytes, 4);
+        let (i0, i1, i2, i3) = unpack_structure!("4B", &bytes[0..4]);
+        (IpAddr::V4(Ipv4Addr::new(i0, i1, i2, i3)), 4)
+    })
+}
+
+/**
+    Writes an IP address in its wire format - returns whether the IPv6 flag has to be set
+    IPv4-mapped IPv6 addresses are always written as IPv4
+**/
+pub fn encode_ip_addr(ip_addr: &IpAddr) -> (bool, Vec<u8>) {
+    match canonical_ip_addr(*ip_addr) {
+        IpAddr::V4(ip_addr) => (false, ip_addr.octets().to_vec()),
+        IpAddr::V6(ip_addr) => (true, ip_addr.octets().to_vec())
+    }
+}
+
+/** Collapses an IPv4-mapped IPv6 address (::ffff:a.b.c.d) into the IPv4 address it carries **/
+pub fn canonical_ip_addr(ip_addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(ipv6_addr) = ip_addr {
+        let octets = ipv6_addr.octets();
+        if octets[0..10].iter().all(|octet| *octet == 0) && octets[10] == 0xff && octets[11] == 0xff {
+            return IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+        }
+    }
+    ip_addr
+}
+
+// Keys are shallow - anything nested deeper than this is not a key
+const MAX_DER_DEPTH: u8 = 8;
+
+/**
+    Makes sure the hostkey is exactly one well-formed DER sequence
+    Catches a wrong IP version flag as well, since the key would start at the wrong offset
+**/
+pub fn validate_hostkey(bytes: &[u8]) -> Result<()> {
+    let (tag, _, rest) = read_der_element(bytes, 0)?;
+    if tag != 0x30 {
+        bail!(ErrorKind::Decode(format!("hostkey has to be a DER sequence, but starts with tag {:#x}", tag)));
+    }
+    if !rest.is_empty() {
+        bail!(ErrorKind::Decode(format!("hostkey is followed by {} trailing bytes", rest.len())));
+    }
+    Ok(())
+}
+
+/** Reads a single DER element - returns its tag, its contents and the bytes following it **/
+fn read_der_element(bytes: &[u8], depth: u8) -> Result<(u8, &[u8], &[u8])> {
+    if depth > MAX_DER_DEPTH {
+        bail!(ErrorKind::Decode("DER structure is nested too deeply".to_string()));
+    }
+    ensure_length!(bytes, 2);
+
+    let tag = bytes[0];
+    if tag & 0x1f == 0x1f {
+        bail!(ErrorKind::Decode("DER tags above 30 are not supported".to_string()));
+    }
+
+    // Messages are limited to 64KiB, so no valid element needs more than two length bytes
+    let (length, header_length) = match bytes[1] {
+        length if length < 0x80 => (length as usize, 2),
+        0x81 => {
+            ensure_length!(bytes, 3);
+            (bytes[2] as usize, 3)
+        },
+        0x82 => {
+            ensure_length!(bytes, 4);
+            ((bytes[2] as usize) << 8 | bytes[3] as usize, 4)
+        },
+        length => bail!(ErrorKind::Decode(format!("DER length byte {:#x} is not supported", length)))
+    };
+    if (header_length == 3 && length < 0x80) || (header_length == 4 && length < 0x100) {
+        bail!(ErrorKind::Decode("DER length is not minimally encoded".to_string()));
+    }
+
+    ensure_length!(bytes, header_length + length);
+    let (contents, rest) = bytes[header_length..].split_at(length);
+
+    // Constructed elements consist of nothing but further elements
+    if tag & 0x20 != 0 {
+        let mut inner = contents;
+        while !inner.is_empty() {
+            inner = read_der_element(inner, depth + 1)?.2;
+        }
+    }
+
+    Ok((tag, contents, rest))
+}
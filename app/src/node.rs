@@ -0,0 +1,78 @@
+// This module is responsible for running a complete onion node inside another application
+use std::sync::mpsc;
+use std::thread;
+
+use errors::*;
+use config;
+use brunch;
+use core::{StreamType, Signal};
+
+/** Controls a running node - can be cloned and handed to other threads **/
+#[derive(Clone)]
+pub struct Controller {
+    sender: mpsc::SyncSender<StreamType>
+}
+impl Controller {
+    /** Re-reads the config file the node was started with **/
+    pub fn reload(&self) -> Result<()> {
+        self.signal(Signal::Reload)
+    }
+
+    /** Destroys all tunnels and stops the node **/
+    pub fn shutdown(&self) -> Result<()> {
+        self.signal(Signal::Shutdown)
+    }
+
+    fn signal(&self, signal: Signal) -> Result<()> {
+        self.sender.send(StreamType::Signal(signal)).chain_err(|| "node has already stopped")
+    }
+}
+
+/** A node running in the background - dropping the handle leaves it running **/
+pub struct NodeHandle {
+    controller: Controller,
+    thread: thread::JoinHandle<Result<()>>
+}
+impl NodeHandle {
+    pub fn controller(&self) -> Controller {
+        self.controller.clone()
+    }
+
+    /** Blocks until the node stops - either on its own or through a controller **/
+    pub fn wait(self) -> Result<()> {
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => bail!("node panicked")
+        }
+    }
+
+    /** Stops the node and waits until all of its tunnels are destroyed **/
+    pub fn shutdown(self) -> Result<()> {
+        // A node which already stopped has nothing left to shut down
+        let _ = self.controller.shutdown();
+        self.wait()
+    }
+}
+
+pub struct Node;
+impl Node {
+    /**
+        Starts the API and P2P listeners along with the core in the background
+        Logging is left to the embedding application (see `logger::init`)
+    **/
+    pub fn start(conf: config::Config) -> Result<NodeHandle> {
+        let (tx, rx) = mpsc::sync_channel(conf.channel_capacity);
+        let controller = Controller {
+            sender: tx.clone()
+        };
+
+        let thread = thread::Builder::new().name("garlic-node".to_string())
+            .spawn(move || brunch::start(conf, tx, rx))
+            .chain_err(|| ErrorKind::Io("spawning node thread".to_string()))?;
+
+        Ok(NodeHandle {
+            controller: controller,
+            thread: thread
+        })
+    }
+}